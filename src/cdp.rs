@@ -0,0 +1,478 @@
+use scrypto::prelude::*;
+use crate::utils::*;
+use crate::pools::lending::lend_pool::LendResourcePool;
+use crate::pools::staking::staking_pool::StakingResourePool;
+use crate::controller::lending_controller::LendingController;
+use crate::events::LiquidationEvent;
+
+#[derive(ScryptoSbor, NonFungibleData)]
+pub struct CollateralDebtPosition {
+    /// collateral resource => amount held by the manager on behalf of the position.
+    #[mutable]
+    pub collaterals: HashMap<ResourceAddress, Decimal>,
+
+    /// underlying token => variable loan share in the lending pool.
+    #[mutable]
+    pub variable_loans: HashMap<ResourceAddress, Decimal>,
+
+    /// underlying token => id of the stable loan receipt held by the manager.
+    #[mutable]
+    pub stable_loans: HashMap<ResourceAddress, NonFungibleLocalId>
+}
+
+
+#[blueprint]
+#[events(LiquidationEvent)]
+mod cdp_mgr{
+
+    enable_method_auth!{
+        roles{
+            admin => updatable_by: [];
+        },
+        methods {
+            //admin
+            set_liquidation_params => restrict_to: [admin, OWNER];
+            set_staking_pool => restrict_to: [admin, OWNER];
+
+            //readonly
+            get_position => PUBLIC;
+            get_health_factor => PUBLIC;
+
+            //business method
+            open_position => PUBLIC;
+            add_collateral => PUBLIC;
+            remove_collateral => PUBLIC;
+            borrow_variable => PUBLIC;
+            borrow_stable => PUBLIC;
+            repay_variable => PUBLIC;
+            repay_stable => PUBLIC;
+            swap_rate_mode => PUBLIC;
+            liquidate => PUBLIC;
+        }
+    }
+
+    struct CollateralDebtManager{
+        // pools and risk parameters, the manager must hold its `cdp_mgr` role.
+        controller: Global<LendingController>,
+        price_oracle: Global<AnyComponent>,
+        quote_token: ResourceAddress,
+
+        cdp_res_mgr: ResourceManager,
+        collateral_vaults: HashMap<ResourceAddress, Vault>,
+        // underlying token => stable loan receipts of all positions
+        stable_loan_vaults: HashMap<ResourceAddress, Vault>,
+        // staking share token (dseXRD) => the staking pool valuing it in XRD.
+        staking_pool_map: HashMap<ResourceAddress, Global<StakingResourePool>>,
+
+        // max share of a debt that can be repaid in one liquidation.
+        close_factor: Decimal,
+        // share of the seized liquidation bonus going to the insurance of the collateral's pool.
+        liquidation_insurance_ratio: Decimal
+    }
+
+    impl CollateralDebtManager {
+
+        pub fn instantiate(
+            controller_addr: ComponentAddress,
+            price_oracle_addr: ComponentAddress,
+            quote_token: ResourceAddress,
+            close_factor: Decimal,
+            liquidation_insurance_ratio: Decimal
+        ) -> (Global<CollateralDebtManager>, Bucket){
+            Self::assert_liquidation_params(close_factor, liquidation_insurance_ratio);
+            let admin_badge = ResourceBuilder::new_fungible(OwnerRole::None)
+                //set divisibility to none to ensure that the admin badge can not be fractionalized.
+                .divisibility(DIVISIBILITY_NONE)
+                .mint_initial_supply(Decimal::ONE);
+
+            let (address_reservation, address) =
+                Runtime::allocate_component_address(CollateralDebtManager::blueprint_id());
+
+            let cdp_res_mgr = ResourceBuilder::new_ruid_non_fungible::<CollateralDebtPosition>(OwnerRole::None)
+                .metadata(metadata!(init{
+                    "symbol" => "dxCDP", locked;
+                    "name" => "DeXian CDP Token", locked;
+                }))
+                .mint_roles(mint_roles! {
+                    minter => rule!(require(global_caller(address)));
+                    minter_updater => rule!(deny_all);
+                })
+                .burn_roles(burn_roles! {
+                    burner => rule!(require(global_caller(address)));
+                    burner_updater => rule!(deny_all);
+                })
+                .non_fungible_data_update_roles(non_fungible_data_update_roles! {
+                    non_fungible_data_updater => rule!(require(global_caller(address)));
+                    non_fungible_data_updater_updater => rule!(deny_all);
+                })
+                .create_with_no_initial_supply();
+
+            let admin_rule = rule!(require(admin_badge.resource_address()));
+            let component = Self{
+                controller: Global::from(controller_addr),
+                price_oracle: Global::from(price_oracle_addr),
+                quote_token,
+                cdp_res_mgr,
+                collateral_vaults: HashMap::new(),
+                stable_loan_vaults: HashMap::new(),
+                staking_pool_map: HashMap::new(),
+                close_factor,
+                liquidation_insurance_ratio
+            }.instantiate()
+            .prepare_to_globalize(OwnerRole::Fixed(admin_rule.clone()))
+            .roles(
+                roles!(
+                    admin => admin_rule;
+                )
+            )
+            .with_address(address_reservation)
+            .globalize();
+
+            (component, admin_badge.into())
+        }
+
+        pub fn set_liquidation_params(&mut self, close_factor: Decimal, liquidation_insurance_ratio: Decimal){
+            Self::assert_liquidation_params(close_factor, liquidation_insurance_ratio);
+            self.close_factor = close_factor;
+            self.liquidation_insurance_ratio = liquidation_insurance_ratio;
+        }
+
+        pub fn set_staking_pool(&mut self, staking_share_token: ResourceAddress, staking_pool_addr: ComponentAddress){
+            self.staking_pool_map.insert(staking_share_token, Global::from(staking_pool_addr));
+        }
+
+        pub fn open_position(&mut self, bucket: Bucket) -> Bucket{
+            let res_addr = bucket.resource_address();
+            let amount = bucket.amount();
+            self.put_collateral(bucket);
+
+            let mut collaterals = HashMap::new();
+            collaterals.insert(res_addr, amount);
+            self.cdp_res_mgr.mint_ruid_non_fungible(CollateralDebtPosition{
+                collaterals,
+                variable_loans: HashMap::new(),
+                stable_loans: HashMap::new()
+            })
+        }
+
+        pub fn add_collateral(&mut self, cdp: Proof, bucket: Bucket){
+            let cdp_id = self.get_cdp_id(cdp);
+            let mut position = self.get_position(cdp_id.clone());
+            let res_addr = bucket.resource_address();
+            let amount = bucket.amount();
+            self.put_collateral(bucket);
+
+            let collateral = position.collaterals.entry(res_addr).or_insert(Decimal::ZERO);
+            *collateral = collateral.checked_add(amount).unwrap();
+            self.save_position(&cdp_id, position);
+        }
+
+        pub fn remove_collateral(&mut self, cdp: Proof, res_addr: ResourceAddress, amount: Decimal) -> Bucket{
+            let cdp_id = self.get_cdp_id(cdp);
+            let mut position = self.get_position(cdp_id.clone());
+            let collateral = position.collaterals.get(&res_addr).copied().unwrap_or(Decimal::ZERO);
+            assert!(amount > Decimal::ZERO && amount <= collateral, "the collateral of the position is insufficient.");
+
+            let remain = collateral.checked_sub(amount).unwrap();
+            if remain == Decimal::ZERO {
+                position.collaterals.remove(&res_addr);
+            }
+            else{
+                position.collaterals.insert(res_addr, remain);
+            }
+            self.assert_position_healthy(&position);
+            self.save_position(&cdp_id, position);
+
+            self.collateral_vaults.get_mut(&res_addr).unwrap().take(amount)
+        }
+
+        pub fn borrow_variable(&mut self, cdp: Proof, underlying_token: ResourceAddress, amount: Decimal) -> Bucket{
+            let cdp_id = self.get_cdp_id(cdp);
+            let mut position = self.get_position(cdp_id.clone());
+            assert!(self.controller.get_risk_params(underlying_token).borrow_enabled, "borrowing of the resource is disabled!");
+
+            let (bucket, variable_share) = self.controller.borrow_variable(underlying_token, amount);
+            let loan_share = position.variable_loans.entry(underlying_token).or_insert(Decimal::ZERO);
+            *loan_share = loan_share.checked_add(variable_share).unwrap();
+
+            self.assert_position_healthy(&position);
+            self.save_position(&cdp_id, position);
+            bucket
+        }
+
+        pub fn borrow_stable(&mut self, cdp: Proof, underlying_token: ResourceAddress, amount: Decimal) -> Bucket{
+            let cdp_id = self.get_cdp_id(cdp);
+            let mut position = self.get_position(cdp_id.clone());
+            // Every stable loan locks its own rate, so a position keeps one stable loan receipt per token.
+            assert!(!position.stable_loans.contains_key(&underlying_token), "repay the outstanding stable loan before borrowing again!");
+            assert!(self.controller.get_risk_params(underlying_token).stable_borrow_enabled, "stable borrowing of the resource is disabled!");
+
+            let (bucket, loan_receipt) = self.controller.borrow_stable(underlying_token, amount);
+            position.stable_loans.insert(underlying_token, loan_receipt.as_non_fungible().non_fungible_local_id());
+            self.stable_loan_vaults.entry(underlying_token).or_insert(Vault::new(loan_receipt.resource_address())).put(loan_receipt);
+
+            self.assert_position_healthy(&position);
+            self.save_position(&cdp_id, position);
+            bucket
+        }
+
+        pub fn repay_variable(&mut self, cdp: Proof, repay_bucket: Bucket) -> Bucket{
+            let cdp_id = self.get_cdp_id(cdp);
+            let mut position = self.get_position(cdp_id.clone());
+            let repay_bucket = self.repay_variable_loan(&mut position, repay_bucket);
+            self.save_position(&cdp_id, position);
+            repay_bucket
+        }
+
+        pub fn repay_stable(&mut self, cdp: Proof, repay_bucket: Bucket) -> Bucket{
+            let cdp_id = self.get_cdp_id(cdp);
+            let mut position = self.get_position(cdp_id.clone());
+            let repay_bucket = self.repay_stable_loan(&mut position, repay_bucket);
+            self.save_position(&cdp_id, position);
+            repay_bucket
+        }
+
+        /// Moves the whole debt of the token to the target rate mode: to stable, the variable shares become a stable
+        /// loan at the current stable rate, otherwise the stable loan becomes variable shares.
+        pub fn swap_rate_mode(&mut self, cdp: Proof, underlying_token: ResourceAddress, to_stable: bool){
+            let cdp_id = self.get_cdp_id(cdp);
+            let mut position = self.get_position(cdp_id.clone());
+
+            if to_stable {
+                assert!(position.variable_loans.contains_key(&underlying_token), "the position has no variable loan of the resource!");
+                // Every stable loan locks its own rate, so a position keeps one stable loan receipt per token.
+                assert!(!position.stable_loans.contains_key(&underlying_token), "repay the outstanding stable loan before swapping to stable!");
+                assert!(self.controller.get_risk_params(underlying_token).stable_borrow_enabled, "stable borrowing of the resource is disabled!");
+                let variable_share = position.variable_loans.remove(&underlying_token).unwrap();
+                let loan_receipt = self.controller.swap_to_stable(underlying_token, variable_share);
+                position.stable_loans.insert(underlying_token, loan_receipt.as_non_fungible().non_fungible_local_id());
+                self.stable_loan_vaults.entry(underlying_token).or_insert(Vault::new(loan_receipt.resource_address())).put(loan_receipt);
+            }
+            else{
+                assert!(position.stable_loans.contains_key(&underlying_token), "the position has no stable loan of the resource!");
+                let loan_id = position.stable_loans.remove(&underlying_token).unwrap();
+                let loan_receipt: Bucket = self.stable_loan_vaults.get_mut(&underlying_token).unwrap()
+                    .as_non_fungible().take_non_fungible(&loan_id).into();
+                let variable_share = self.controller.swap_to_variable(underlying_token, loan_receipt);
+                let loan_share = position.variable_loans.entry(underlying_token).or_insert(Decimal::ZERO);
+                *loan_share = loan_share.checked_add(variable_share).unwrap();
+            }
+
+            self.save_position(&cdp_id, position);
+        }
+
+        pub fn liquidate(&mut self, cdp_id: NonFungibleLocalId, collateral_token: ResourceAddress, mut repay_bucket: Bucket) -> (Bucket, Bucket){
+            let mut position = self.get_position(cdp_id.clone());
+            assert!(self.calc_health_factor(&position) < Decimal::ONE, "the position is healthy and can not be liquidated!");
+            let collateral_amount = position.collaterals.get(&collateral_token).copied().unwrap_or(Decimal::ZERO);
+            assert!(collateral_amount > Decimal::ZERO, "the position holds no such collateral!");
+            let debt_token = repay_bucket.resource_address();
+            let debt = self.get_token_debt(&position, &debt_token);
+            assert!(debt > Decimal::ZERO, "the position has no debt of the resource!");
+
+            let risk_params = self.controller.get_risk_params(collateral_token);
+            let bonus_ratio = Decimal::ONE.checked_add(risk_params.liquidation_bonus).unwrap();
+            let close_amount = debt.checked_mul(self.close_factor).unwrap();
+            let mut repay_amount = if repay_bucket.amount() < close_amount { repay_bucket.amount() } else { close_amount };
+
+            let debt_price = self.get_price(&debt_token);
+            let collateral_price = self.get_price(&collateral_token);
+            let max_repay = ceil(collateral_amount
+                .checked_mul(collateral_price).unwrap()
+                .checked_div(debt_price.checked_mul(bonus_ratio).unwrap()).unwrap()
+            );
+            if repay_amount > max_repay {
+                repay_amount = max_repay;
+            }
+            assert!(repay_amount > Decimal::ZERO, "the repayment is too small to liquidate!");
+
+            let mut repay = repay_bucket.take(repay_amount);
+            if position.variable_loans.contains_key(&debt_token) {
+                repay = self.repay_variable_loan(&mut position, repay);
+            }
+            if !repay.is_empty() && position.stable_loans.contains_key(&debt_token) {
+                repay = self.repay_stable_loan(&mut position, repay);
+            }
+            // the collateral is seized for what the loans took, not for the change.
+            let repay_amount = repay_amount.checked_sub(repay.amount()).unwrap();
+            repay_bucket.put(repay);
+
+            let mut seize_amount = floor(repay_amount
+                .checked_mul(debt_price).unwrap()
+                .checked_mul(bonus_ratio).unwrap()
+                .checked_div(collateral_price).unwrap()
+            );
+            if seize_amount > collateral_amount {
+                seize_amount = collateral_amount;
+            }
+            // the insurance share of the bonus part of the seizure. Without a pool of the collateral
+            // there is no insurance to fund, so it is left to the position.
+            let mut insurance_amount = floor(seize_amount
+                .checked_mul(risk_params.liquidation_bonus.checked_mul(self.liquidation_insurance_ratio).unwrap()).unwrap()
+                .checked_div(bonus_ratio).unwrap()
+            );
+            if !self.controller.get_underlying_tokens().contains(&collateral_token) {
+                seize_amount = seize_amount.checked_sub(insurance_amount).unwrap();
+                insurance_amount = Decimal::ZERO;
+            }
+
+            let remain = collateral_amount.checked_sub(seize_amount).unwrap();
+            if remain == Decimal::ZERO {
+                position.collaterals.remove(&collateral_token);
+            }
+            else{
+                position.collaterals.insert(collateral_token, remain);
+            }
+            self.save_position(&cdp_id, position);
+
+            Runtime::emit_event(LiquidationEvent{
+                cdp_id,
+                debt_token,
+                repay_amount,
+                collateral_token,
+                seize_amount,
+                insurance_amount
+            });
+
+            let mut seize_bucket = self.collateral_vaults.get_mut(&collateral_token).unwrap().take(seize_amount);
+            if insurance_amount > Decimal::ZERO {
+                self.controller.add_insurance(seize_bucket.take(insurance_amount));
+            }
+            (seize_bucket, repay_bucket)
+        }
+
+        pub fn get_position(&self, cdp_id: NonFungibleLocalId) -> CollateralDebtPosition{
+            self.cdp_res_mgr.get_non_fungible_data::<CollateralDebtPosition>(&cdp_id)
+        }
+
+        pub fn get_health_factor(&self, cdp_id: NonFungibleLocalId) -> Decimal{
+            self.calc_health_factor(&self.get_position(cdp_id))
+        }
+
+        fn repay_variable_loan(&self, position: &mut CollateralDebtPosition, repay_bucket: Bucket) -> Bucket{
+            let underlying_token = repay_bucket.resource_address();
+            assert!(position.variable_loans.contains_key(&underlying_token), "the position has no variable loan of the resource!");
+            let loan_share = *position.variable_loans.get(&underlying_token).unwrap();
+
+            let (repay_bucket, repay_share) = self.controller.repay_variable(repay_bucket, loan_share);
+
+            if repay_share >= loan_share {
+                position.variable_loans.remove(&underlying_token);
+            }
+            else{
+                position.variable_loans.insert(underlying_token, loan_share.checked_sub(repay_share).unwrap());
+            }
+            repay_bucket
+        }
+
+        fn repay_stable_loan(&mut self, position: &mut CollateralDebtPosition, repay_bucket: Bucket) -> Bucket{
+            let underlying_token = repay_bucket.resource_address();
+            assert!(position.stable_loans.contains_key(&underlying_token), "the position has no stable loan of the resource!");
+            let loan_id = position.stable_loans.get(&underlying_token).unwrap().clone();
+
+            let loan_vault = self.stable_loan_vaults.get_mut(&underlying_token).unwrap();
+            let loan_receipt: Bucket = loan_vault.as_non_fungible().take_non_fungible(&loan_id).into();
+            let (repay_bucket, loan_receipt) = self.controller.repay_stable(repay_bucket, loan_receipt);
+            match loan_receipt {
+                Some(loan_receipt) => loan_vault.put(loan_receipt),
+                None => {
+                    position.stable_loans.remove(&underlying_token);
+                }
+            }
+            repay_bucket
+        }
+
+        fn put_collateral(&mut self, bucket: Bucket){
+            let res_addr = bucket.resource_address();
+            assert!(self.controller.get_risk_params(res_addr).collateral_enabled, "the resource is not accepted as collateral!");
+            self.collateral_vaults.entry(res_addr).or_insert(Vault::new(res_addr)).put(bucket);
+        }
+
+        fn get_cdp_id(&self, cdp: Proof) -> NonFungibleLocalId{
+            let checked_proof = cdp.check(self.cdp_res_mgr.address());
+            checked_proof.as_non_fungible().non_fungible_local_id()
+        }
+
+        fn save_position(&self, cdp_id: &NonFungibleLocalId, position: CollateralDebtPosition){
+            self.cdp_res_mgr.update_non_fungible_data(cdp_id, "collaterals", position.collaterals);
+            self.cdp_res_mgr.update_non_fungible_data(cdp_id, "variable_loans", position.variable_loans);
+            self.cdp_res_mgr.update_non_fungible_data(cdp_id, "stable_loans", position.stable_loans);
+        }
+
+        fn get_lend_pool(&self, underlying_token: &ResourceAddress) -> Global<LendResourcePool>{
+            self.controller.get_pool(*underlying_token)
+        }
+
+        fn assert_position_healthy(&self, position: &CollateralDebtPosition){
+            let (borrow_limit, _) = self.get_collateral_value(position);
+            let debt_value = self.get_debt_value(position);
+            assert!(debt_value <= borrow_limit, "the debt exceeds the borrowing limit of the position!");
+        }
+
+        fn calc_health_factor(&self, position: &CollateralDebtPosition) -> Decimal{
+            let debt_value = self.get_debt_value(position);
+            if debt_value == Decimal::ZERO {
+                return Decimal::MAX;
+            }
+            let (_, liquidation_value) = self.get_collateral_value(position);
+            liquidation_value.checked_div(debt_value).unwrap()
+        }
+
+        /// Returns the collateral value weighted by loan to value and by liquidation threshold.
+        fn get_collateral_value(&self, position: &CollateralDebtPosition) -> (Decimal, Decimal){
+            position.collaterals.iter().fold((Decimal::ZERO, Decimal::ZERO), |(borrow_limit, liquidation_value), (res_addr, amount)|{
+                let risk_params = self.controller.get_risk_params(*res_addr);
+                let value = amount.checked_mul(self.get_price(res_addr)).unwrap();
+                (
+                    borrow_limit.checked_add(value.checked_mul(risk_params.ltv).unwrap()).unwrap(),
+                    liquidation_value.checked_add(value.checked_mul(risk_params.liquidation_threshold).unwrap()).unwrap()
+                )
+            })
+        }
+
+        fn get_token_debt(&self, position: &CollateralDebtPosition, underlying_token: &ResourceAddress) -> Decimal{
+            let variable_debt = match position.variable_loans.get(underlying_token) {
+                Some(loan_share) => {
+                    let (_, loan_index) = self.get_lend_pool(underlying_token).get_current_index();
+                    loan_share.checked_mul(loan_index).unwrap()
+                },
+                None => Decimal::ZERO
+            };
+            let stable_debt = match position.stable_loans.get(underlying_token) {
+                Some(loan_id) => self.get_lend_pool(underlying_token).get_stable_loan_debt(loan_id.clone()),
+                None => Decimal::ZERO
+            };
+            variable_debt.checked_add(stable_debt).unwrap()
+        }
+
+        fn get_debt_value(&self, position: &CollateralDebtPosition) -> Decimal{
+            let variable_debt = position.variable_loans.iter().fold(Decimal::ZERO, |sum, (underlying_token, loan_share)|{
+                let (_, loan_index) = self.get_lend_pool(underlying_token).get_current_index();
+                let debt = loan_share.checked_mul(loan_index).unwrap();
+                sum.checked_add(debt.checked_mul(self.get_price(underlying_token)).unwrap()).unwrap()
+            });
+            position.stable_loans.iter().fold(variable_debt, |sum, (underlying_token, loan_id)|{
+                let debt = self.get_lend_pool(underlying_token).get_stable_loan_debt(loan_id.clone());
+                sum.checked_add(debt.checked_mul(self.get_price(underlying_token)).unwrap()).unwrap()
+            })
+        }
+
+        fn assert_liquidation_params(close_factor: Decimal, liquidation_insurance_ratio: Decimal){
+            assert!(close_factor > Decimal::ZERO && close_factor <= Decimal::ONE, "the close factor must be in (0, 1]!");
+            assert!(
+                liquidation_insurance_ratio >= Decimal::ZERO && liquidation_insurance_ratio <= Decimal::ONE,
+                "the liquidation insurance ratio must be in [0, 1]!"
+            );
+        }
+
+        fn get_price(&self, res_addr: &ResourceAddress) -> Decimal{
+            if *res_addr == self.quote_token {
+                return Decimal::ONE;
+            }
+            if let Some(staking_pool) = self.staking_pool_map.get(res_addr) {
+                return staking_pool.get_value_per_share().checked_mul(self.get_price(&XRD)).unwrap();
+            }
+            self.price_oracle.call_raw::<Decimal>("get_price", scrypto_args!(*res_addr, self.quote_token))
+        }
+    }
+}
@@ -0,0 +1,268 @@
+use scrypto::prelude::*;
+use crate::interest::InterestModel;
+use crate::pools::lending::lend_pool::LendResourcePool;
+
+#[derive(ScryptoSbor, Eq, PartialEq, Debug, Clone)]
+pub struct RiskParams {
+    /// share of the collateral value that can be borrowed against.
+    pub ltv: Decimal,
+    /// share of the collateral value at which the position becomes liquidatable.
+    pub liquidation_threshold: Decimal,
+    /// extra collateral paid to the liquidator on top of the repaid debt value.
+    pub liquidation_bonus: Decimal,
+    /// `Decimal::MAX` leaves the supply uncapped.
+    pub supply_cap: Decimal,
+    /// `Decimal::MAX` leaves the borrowing uncapped.
+    pub borrow_cap: Decimal,
+    pub collateral_enabled: bool,
+    pub borrow_enabled: bool,
+    pub stable_borrow_enabled: bool
+}
+
+
+#[blueprint]
+mod lending_controller{
+
+    enable_method_auth!{
+        roles{
+            admin => updatable_by: [];
+            cdp_mgr => updatable_by: [admin, OWNER];
+        },
+        methods {
+            //admin
+            new_pool => restrict_to: [admin, OWNER];
+            set_risk_params => restrict_to: [admin, OWNER];
+            set_flash_loan_fee_ratio => restrict_to: [admin, OWNER];
+            set_insurance_ratio => restrict_to: [admin, OWNER];
+            set_rebalance_params => restrict_to: [admin, OWNER];
+            set_treasury => restrict_to: [admin, OWNER];
+            withdraw_insurance => restrict_to: [admin, OWNER];
+
+            //cdp
+            borrow_variable => restrict_to: [cdp_mgr];
+            borrow_stable => restrict_to: [cdp_mgr];
+            repay_variable => restrict_to: [cdp_mgr];
+            repay_stable => restrict_to: [cdp_mgr];
+            swap_to_stable => restrict_to: [cdp_mgr];
+            swap_to_variable => restrict_to: [cdp_mgr];
+            add_insurance => restrict_to: [cdp_mgr];
+
+            //readonly
+            get_pool => PUBLIC;
+            get_deposit_share_token => PUBLIC;
+            get_underlying_tokens => PUBLIC;
+            get_risk_params => PUBLIC;
+            get_treasury => PUBLIC;
+        }
+    }
+
+    struct LendingController{
+        // the only badge allowed to borrow from and repay to the lending pools.
+        pool_mgr_badge: Vault,
+        // underlying token => lending pool
+        pool_map: HashMap<ResourceAddress, Global<LendResourcePool>>,
+        // underlying token => deposit share token
+        deposit_share_map: HashMap<ResourceAddress, ResourceAddress>,
+        // underlying tokens and collaterals => risk parameters
+        risk_params_map: HashMap<ResourceAddress, RiskParams>,
+        // the account the withdrawn insurance is deposited to.
+        treasury: Option<Global<Account>>
+    }
+
+    impl LendingController {
+
+        /// The `cdp_mgr` role starts as deny all, the admin sets it to the CDP manager once that is instantiated.
+        pub fn instantiate() -> (Global<LendingController>, Bucket){
+            let admin_badge = ResourceBuilder::new_fungible(OwnerRole::None)
+                //set divisibility to none to ensure that the admin badge can not be fractionalized.
+                .divisibility(DIVISIBILITY_NONE)
+                .mint_initial_supply(Decimal::ONE);
+
+            let pool_mgr_badge = ResourceBuilder::new_fungible(OwnerRole::None)
+                .divisibility(DIVISIBILITY_NONE)
+                .mint_initial_supply(Decimal::ONE);
+
+            let admin_rule = rule!(require(admin_badge.resource_address()));
+            let component = Self{
+                pool_mgr_badge: Vault::with_bucket(pool_mgr_badge.into()),
+                pool_map: HashMap::new(),
+                deposit_share_map: HashMap::new(),
+                risk_params_map: HashMap::new(),
+                treasury: None
+            }.instantiate()
+            .prepare_to_globalize(OwnerRole::Fixed(admin_rule.clone()))
+            .roles(
+                roles!(
+                    admin => admin_rule;
+                    cdp_mgr => rule!(deny_all);
+                )
+            ).globalize();
+
+            (component, admin_badge.into())
+        }
+
+        pub fn new_pool(
+            &mut self,
+            underlying_token: ResourceAddress,
+            interest_model_cmp_addr: ComponentAddress,
+            interest_model: InterestModel,
+            insurance_ratio: Decimal,
+            risk_params: RiskParams
+        ) -> (ComponentAddress, ResourceAddress){
+            assert!(!self.pool_map.contains_key(&underlying_token), "the pool of the underlying token already exists!");
+            let (address_reservation, address) =
+                Runtime::allocate_component_address(LendResourcePool::blueprint_id());
+            let pool_mgr_rule = rule!(require(self.pool_mgr_badge.resource_address()));
+            let (pool, deposit_share_token) = Blueprint::<LendResourcePool>::instantiate(
+                underlying_token,
+                interest_model_cmp_addr,
+                interest_model,
+                insurance_ratio,
+                OwnerRole::Fixed(pool_mgr_rule.clone()),
+                pool_mgr_rule,
+                Some(address_reservation)
+            );
+
+            self.pool_map.insert(underlying_token, pool);
+            self.deposit_share_map.insert(underlying_token, deposit_share_token);
+            self.set_risk_params(underlying_token, risk_params);
+            (address, deposit_share_token)
+        }
+
+        pub fn set_risk_params(&mut self, res_addr: ResourceAddress, risk_params: RiskParams){
+            assert!(
+                risk_params.ltv >= Decimal::ZERO && risk_params.ltv <= risk_params.liquidation_threshold,
+                "the loan to value must be in [0, liquidation threshold]!"
+            );
+            assert!(risk_params.liquidation_bonus >= Decimal::ZERO, "the liquidation bonus must not be negative!");
+            // Seizing the collateral of a liquidatable position must not cost more than the position holds.
+            assert!(
+                risk_params.liquidation_threshold.checked_mul(Decimal::ONE.checked_add(risk_params.liquidation_bonus).unwrap()).unwrap() < Decimal::ONE,
+                "the liquidation threshold with bonus must be less than 1!"
+            );
+            assert!(
+                risk_params.supply_cap >= Decimal::ZERO && risk_params.borrow_cap >= Decimal::ZERO,
+                "the caps must not be negative!"
+            );
+            if let Some(pool) = self.pool_map.get(&res_addr) {
+                let mut pool = *pool;
+                self.pool_mgr_badge.as_fungible().authorize_with_amount(Decimal::ONE, ||{
+                    pool.set_caps(risk_params.supply_cap, risk_params.borrow_cap)
+                });
+            }
+            self.risk_params_map.insert(res_addr, risk_params);
+        }
+
+        pub fn set_flash_loan_fee_ratio(&mut self, underlying_token: ResourceAddress, flash_loan_fee_ratio: Decimal){
+            let mut pool = self.get_lend_pool(&underlying_token);
+            self.pool_mgr_badge.as_fungible().authorize_with_amount(Decimal::ONE, ||{
+                pool.set_flash_loan_fee_ratio(flash_loan_fee_ratio)
+            });
+        }
+
+        pub fn set_insurance_ratio(&mut self, underlying_token: ResourceAddress, insurance_ratio: Decimal){
+            let mut pool = self.get_lend_pool(&underlying_token);
+            self.pool_mgr_badge.as_fungible().authorize_with_amount(Decimal::ONE, ||{
+                pool.set_insurance_ratio(insurance_ratio)
+            });
+        }
+
+        pub fn set_rebalance_params(&mut self, underlying_token: ResourceAddress, rebalance_utilization: Decimal, rebalance_deposit_ratio: Decimal){
+            let mut pool = self.get_lend_pool(&underlying_token);
+            self.pool_mgr_badge.as_fungible().authorize_with_amount(Decimal::ONE, ||{
+                pool.set_rebalance_params(rebalance_utilization, rebalance_deposit_ratio)
+            });
+        }
+
+        pub fn set_treasury(&mut self, treasury: ComponentAddress){
+            self.treasury = Some(Global::from(treasury));
+        }
+
+        pub fn get_treasury(&self) -> Option<ComponentAddress>{
+            self.treasury.map(|treasury| treasury.address())
+        }
+
+        /// The insurance only leaves the pool to the treasury account.
+        pub fn withdraw_insurance(&mut self, underlying_token: ResourceAddress, amount: Decimal){
+            assert!(self.treasury.is_some(), "the treasury is not set!");
+            let treasury = self.treasury.unwrap();
+            let mut pool = self.get_lend_pool(&underlying_token);
+            let insurance_bucket = self.pool_mgr_badge.as_fungible().authorize_with_amount(Decimal::ONE, ||{
+                pool.withdraw_insurance(amount)
+            });
+            treasury.try_deposit_or_abort(insurance_bucket, None);
+        }
+
+        pub fn borrow_variable(&self, underlying_token: ResourceAddress, amount: Decimal) -> (Bucket, Decimal){
+            let mut pool = self.get_lend_pool(&underlying_token);
+            self.pool_mgr_badge.as_fungible().authorize_with_amount(Decimal::ONE, ||{
+                pool.borrow_variable(amount)
+            })
+        }
+
+        pub fn borrow_stable(&self, underlying_token: ResourceAddress, amount: Decimal) -> (Bucket, Bucket){
+            let mut pool = self.get_lend_pool(&underlying_token);
+            self.pool_mgr_badge.as_fungible().authorize_with_amount(Decimal::ONE, ||{
+                pool.borrow_stable(amount)
+            })
+        }
+
+        pub fn repay_variable(&self, repay_bucket: Bucket, loan_share: Decimal) -> (Bucket, Decimal){
+            let mut pool = self.get_lend_pool(&repay_bucket.resource_address());
+            self.pool_mgr_badge.as_fungible().authorize_with_amount(Decimal::ONE, ||{
+                pool.repay_variable(repay_bucket, loan_share)
+            })
+        }
+
+        pub fn repay_stable(&self, repay_bucket: Bucket, loan_receipt: Bucket) -> (Bucket, Option<Bucket>){
+            let mut pool = self.get_lend_pool(&repay_bucket.resource_address());
+            self.pool_mgr_badge.as_fungible().authorize_with_amount(Decimal::ONE, ||{
+                pool.repay_stable(repay_bucket, loan_receipt)
+            })
+        }
+
+        pub fn swap_to_stable(&self, underlying_token: ResourceAddress, variable_share: Decimal) -> Bucket{
+            let mut pool = self.get_lend_pool(&underlying_token);
+            self.pool_mgr_badge.as_fungible().authorize_with_amount(Decimal::ONE, ||{
+                pool.swap_to_stable(variable_share)
+            })
+        }
+
+        pub fn swap_to_variable(&self, underlying_token: ResourceAddress, loan_receipt: Bucket) -> Decimal{
+            let mut pool = self.get_lend_pool(&underlying_token);
+            self.pool_mgr_badge.as_fungible().authorize_with_amount(Decimal::ONE, ||{
+                pool.swap_to_variable(loan_receipt)
+            })
+        }
+
+        pub fn add_insurance(&self, bucket: Bucket){
+            let mut pool = self.get_lend_pool(&bucket.resource_address());
+            self.pool_mgr_badge.as_fungible().authorize_with_amount(Decimal::ONE, ||{
+                pool.add_insurance(bucket)
+            });
+        }
+
+        pub fn get_pool(&self, underlying_token: ResourceAddress) -> Global<LendResourcePool>{
+            self.get_lend_pool(&underlying_token)
+        }
+
+        pub fn get_deposit_share_token(&self, underlying_token: ResourceAddress) -> ResourceAddress{
+            assert!(self.deposit_share_map.contains_key(&underlying_token), "there is no pool for the underlying token!");
+            *self.deposit_share_map.get(&underlying_token).unwrap()
+        }
+
+        pub fn get_underlying_tokens(&self) -> Vec<ResourceAddress>{
+            self.pool_map.keys().cloned().collect()
+        }
+
+        pub fn get_risk_params(&self, res_addr: ResourceAddress) -> RiskParams{
+            assert!(self.risk_params_map.contains_key(&res_addr), "there are no risk parameters for the resource!");
+            self.risk_params_map.get(&res_addr).unwrap().clone()
+        }
+
+        fn get_lend_pool(&self, underlying_token: &ResourceAddress) -> Global<LendResourcePool>{
+            assert!(self.pool_map.contains_key(underlying_token), "there is no pool for the underlying token!");
+            *self.pool_map.get(underlying_token).unwrap()
+        }
+    }
+}
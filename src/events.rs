@@ -0,0 +1,119 @@
+use scrypto::prelude::*;
+
+#[derive(ScryptoSbor, ScryptoEvent, Debug, Clone, PartialEq, Eq)]
+pub struct DepositEvent {
+    pub amount: Decimal,
+    pub share_amount: Decimal
+}
+
+#[derive(ScryptoSbor, ScryptoEvent, Debug, Clone, PartialEq, Eq)]
+pub struct WithdrawEvent {
+    pub amount: Decimal,
+    pub share_amount: Decimal
+}
+
+#[derive(ScryptoSbor, ScryptoEvent, Debug, Clone, PartialEq, Eq)]
+pub struct BorrowEvent {
+    pub amount: Decimal,
+    pub stable: bool,
+    /// the variable rate, or the rate of the stable loan.
+    pub rate: Decimal
+}
+
+#[derive(ScryptoSbor, ScryptoEvent, Debug, Clone, PartialEq, Eq)]
+pub struct RepayEvent {
+    pub amount: Decimal,
+    pub stable: bool
+}
+
+#[derive(ScryptoSbor, ScryptoEvent, Debug, Clone, PartialEq, Eq)]
+pub struct SwapRateModeEvent {
+    /// the debt moved to the other rate mode.
+    pub amount: Decimal,
+    /// true when the loan becomes a stable one.
+    pub stable: bool,
+    /// the variable rate, or the rate of the new stable loan.
+    pub rate: Decimal
+}
+
+#[derive(ScryptoSbor, ScryptoEvent, Debug, Clone, PartialEq, Eq)]
+pub struct StableRateRebalanceEvent {
+    pub loan_id: NonFungibleLocalId,
+    pub debt: Decimal,
+    pub previous_rate: Decimal,
+    pub rate: Decimal
+}
+
+#[derive(ScryptoSbor, ScryptoEvent, Debug, Clone, PartialEq, Eq)]
+pub struct FlashBorrowEvent {
+    pub amount: Decimal,
+    pub fee: Decimal
+}
+
+#[derive(ScryptoSbor, ScryptoEvent, Debug, Clone, PartialEq, Eq)]
+pub struct FlashRepayEvent {
+    pub amount: Decimal,
+    pub fee: Decimal
+}
+
+#[derive(ScryptoSbor, ScryptoEvent, Debug, Clone, PartialEq, Eq)]
+pub struct LiquidationEvent {
+    pub cdp_id: NonFungibleLocalId,
+    pub debt_token: ResourceAddress,
+    pub repay_amount: Decimal,
+    pub collateral_token: ResourceAddress,
+    /// taken from the position, the insurance amount included.
+    pub seize_amount: Decimal,
+    pub insurance_amount: Decimal
+}
+
+/// Shares are minted for the whole amount, the part kept by the buffer is not staked.
+#[derive(ScryptoSbor, ScryptoEvent, Debug, Clone, PartialEq, Eq)]
+pub struct ContributeEvent {
+    pub amount: Decimal,
+    pub buffered: Decimal,
+    pub staked: Decimal,
+    pub share_amount: Decimal
+}
+
+#[derive(ScryptoSbor, ScryptoEvent, Debug, Clone, PartialEq, Eq)]
+pub struct StakeEvent {
+    pub validator: ComponentAddress,
+    pub amount: Decimal,
+    pub share_amount: Decimal
+}
+
+/// Claimed XRD staked again by rebalancing, it mints no shares.
+#[derive(ScryptoSbor, ScryptoEvent, Debug, Clone, PartialEq, Eq)]
+pub struct RestakeEvent {
+    pub validator: ComponentAddress,
+    pub amount: Decimal
+}
+
+/// An instant redemption is paid from the buffer, so it has no validator nor claim NFT.
+#[derive(ScryptoSbor, ScryptoEvent, Debug, Clone, PartialEq, Eq)]
+pub struct RedeemEvent {
+    pub validator: Option<ComponentAddress>,
+    pub share_amount: Decimal,
+    /// the value of the shares, the redeemer gets it less the fee.
+    pub redeem_value: Decimal,
+    pub fee: Decimal,
+    pub claim_nft_id: Option<NonFungibleLocalId>
+}
+
+#[derive(ScryptoSbor, ScryptoEvent, Debug, Clone, PartialEq, Eq)]
+pub struct ValidatorSnapshotEvent {
+    pub validator: ComponentAddress,
+    pub last_lsu: Decimal,
+    pub last_staked: Decimal,
+    pub epoch_at: u64
+}
+
+#[derive(ScryptoSbor, ScryptoEvent, Debug, Clone, PartialEq, Eq)]
+pub struct RateUpdateEvent {
+    pub deposit_rate: Decimal,
+    pub variable_rate: Decimal,
+    pub stable_rate: Decimal,
+    pub deposit_index: Decimal,
+    pub loan_index: Decimal
+}
@@ -3,62 +3,587 @@ use scrypto::prelude::*;
 #[derive(ScryptoSbor, Eq, PartialEq, Debug, Clone)]
 pub enum InterestModel {
     Default,
-    StableCoin
+    StableCoin,
+    Kinked,
+    /// a parameter set added to the model component under this name.
+    Named(String),
+    /// a state of an `AdaptiveInterestModel` under this name, the pool reports its borrow ratio to it.
+    Adaptive(String)
+}
+
+pub const DEFAULT_CURVE: &str = "default";
+pub const STABLE_COIN_CURVE: &str = "stable_coin";
+pub const KINKED_CURVE: &str = "kinked";
+
+impl InterestModel {
+    pub fn curve_name(&self) -> String{
+        match self {
+            InterestModel::Default => DEFAULT_CURVE.to_owned(),
+            InterestModel::StableCoin => STABLE_COIN_CURVE.to_owned(),
+            InterestModel::Kinked => KINKED_CURVE.to_owned(),
+            InterestModel::Named(name) | InterestModel::Adaptive(name) => name.clone()
+        }
+    }
+}
+
+/// upper bound of every rate coefficient, 1000% a year.
+pub const MAX_COEFFICIENT: Decimal = dec!("10");
+
+#[derive(ScryptoSbor, Eq, PartialEq, Debug, Clone, Copy)]
+pub enum CurveCoefficient {
+    Primary,
+    Quadratic,
+    BaseRate,
+    OptimalUtilization,
+    Slope1,
+    Slope2
+}
+
+/// Premium on top of the stable base rate, growing with the stable share of the debt
+/// and with the utilization above `utilization_threshold`:
+/// stable_ratio_slope * stable_ratio + utilization_slope * (r - threshold) / (1 - threshold).
+#[derive(ScryptoSbor, Eq, PartialEq, Debug, Clone, Copy)]
+pub struct StablePremium {
+    pub stable_ratio_slope: Decimal,
+    pub utilization_threshold: Decimal,
+    pub utilization_slope: Decimal
+}
+
+impl StablePremium {
+    pub fn validate(&self){
+        assert!(
+            self.utilization_threshold >= Decimal::ZERO && self.utilization_threshold < Decimal::ONE,
+            "the utilization threshold must be in [0, 1)!"
+        );
+        assert!(
+            self.stable_ratio_slope >= Decimal::ZERO && self.utilization_slope >= Decimal::ZERO
+            && self.stable_ratio_slope <= MAX_COEFFICIENT && self.utilization_slope <= MAX_COEFFICIENT,
+            "the slopes must be in [0, 10]!"
+        );
+    }
+
+    pub fn get_premium(&self, borrow_ratio: Decimal, stable_ratio: Decimal) -> Decimal{
+        let r = if borrow_ratio > Decimal::ONE { Decimal::ONE } else { borrow_ratio };
+        let s = if stable_ratio > Decimal::ONE { Decimal::ONE } else { stable_ratio };
+        let stable_premium = self.stable_ratio_slope.checked_mul(s).unwrap();
+        if r <= self.utilization_threshold {
+            return stable_premium;
+        }
+        let excess = r.checked_sub(self.utilization_threshold).unwrap()
+            .checked_div(Decimal::ONE.checked_sub(self.utilization_threshold).unwrap()).unwrap();
+        stable_premium.checked_add(self.utilization_slope.checked_mul(excess).unwrap()).unwrap()
+    }
+}
+
+/// A premium change waiting for the timelock, it takes effect from `effective_epoch` on.
+#[derive(ScryptoSbor, Eq, PartialEq, Debug, Clone, Copy)]
+pub struct PendingStablePremium {
+    pub stable_premium: StablePremium,
+    pub effective_epoch: u64
+}
+
+/// A curve change waiting for the timelock, it takes effect from `effective_epoch` on.
+#[derive(ScryptoSbor, Eq, PartialEq, Debug, Clone)]
+pub struct PendingCurve {
+    pub curve: InterestCurve,
+    pub effective_epoch: u64
+}
+
+/// A lower timelock waiting for the current one, it takes effect from `effective_epoch` on.
+#[derive(ScryptoSbor, Eq, PartialEq, Debug, Clone, Copy)]
+pub struct PendingTimelock {
+    pub timelock_epochs: u64,
+    pub effective_epoch: u64
+}
+
+#[derive(ScryptoSbor, Eq, PartialEq, Debug, Clone)]
+pub enum InterestCurve {
+    /// primary * r + quadratic * r**2
+    Quadratic{ primary: Decimal, quadratic: Decimal },
+    /// primary * r**4 + quadratic * r**8
+    StableCoin{ primary: Decimal, quadratic: Decimal },
+    Kinked(KinkedParams)
+}
+
+impl InterestCurve {
+    pub fn validate(&self){
+        match self {
+            InterestCurve::Quadratic{ primary, quadratic } | InterestCurve::StableCoin{ primary, quadratic } => assert!(
+                *primary >= Decimal::ZERO && *quadratic >= Decimal::ZERO
+                && *primary <= MAX_COEFFICIENT && *quadratic <= MAX_COEFFICIENT,
+                "the coefficients must be in [0, 10]!"
+            ),
+            InterestCurve::Kinked(params) => params.validate()
+        }
+    }
+
+    pub fn with_coefficient(&self, coefficient: CurveCoefficient, value: Decimal) -> InterestCurve{
+        let mut curve = self.clone();
+        match (&mut curve, coefficient) {
+            (InterestCurve::Quadratic{ primary, .. }, CurveCoefficient::Primary)
+            | (InterestCurve::StableCoin{ primary, .. }, CurveCoefficient::Primary) => *primary = value,
+            (InterestCurve::Quadratic{ quadratic, .. }, CurveCoefficient::Quadratic)
+            | (InterestCurve::StableCoin{ quadratic, .. }, CurveCoefficient::Quadratic) => *quadratic = value,
+            (InterestCurve::Kinked(params), CurveCoefficient::BaseRate) => params.base_rate = value,
+            (InterestCurve::Kinked(params), CurveCoefficient::OptimalUtilization) => params.optimal_utilization = value,
+            (InterestCurve::Kinked(params), CurveCoefficient::Slope1) => params.slope1 = value,
+            (InterestCurve::Kinked(params), CurveCoefficient::Slope2) => params.slope2 = value,
+            _ => panic!("the curve has no such coefficient!")
+        }
+        curve
+    }
+
+    pub fn get_rate(&self, borrow_ratio: Decimal) -> Decimal{
+        match self {
+            InterestCurve::Quadratic{ primary, quadratic } => {
+                let r = if borrow_ratio > Decimal::ONE { Decimal::ONE } else { borrow_ratio };
+                r.checked_mul(*primary).unwrap()
+                    .checked_add(r.checked_powi(2).unwrap().checked_mul(*quadratic).unwrap()).unwrap()
+            },
+            InterestCurve::StableCoin{ primary, quadratic } => {
+                let r2 = if borrow_ratio > Decimal::ONE { Decimal::ONE} else{ borrow_ratio.checked_powi(2).unwrap()};
+                let r4 = r2.checked_powi(2).unwrap();
+                let r8 = r2.checked_powi(4).unwrap();
+                primary.checked_mul(r4).unwrap().checked_add(quadratic.checked_mul(r8).unwrap()).unwrap()
+            },
+            InterestCurve::Kinked(params) => params.get_rate(borrow_ratio)
+        }
+    }
+}
+
+/// base_rate + slope1 * r / optimal_utilization below the optimal utilization,
+/// base_rate + slope1 + slope2 * (r - optimal_utilization) / (1 - optimal_utilization) above it.
+#[derive(ScryptoSbor, Eq, PartialEq, Debug, Clone, Copy)]
+pub struct KinkedParams {
+    pub base_rate: Decimal,
+    pub optimal_utilization: Decimal,
+    pub slope1: Decimal,
+    pub slope2: Decimal
+}
+
+impl KinkedParams {
+    pub fn validate(&self){
+        assert!(
+            self.optimal_utilization > Decimal::ZERO && self.optimal_utilization < Decimal::ONE,
+            "the optimal utilization must be in (0, 1)!"
+        );
+        assert!(
+            self.base_rate >= Decimal::ZERO && self.slope1 >= Decimal::ZERO && self.slope2 >= Decimal::ZERO,
+            "the base rate and slopes must not be negative!"
+        );
+        assert!(
+            self.base_rate <= MAX_COEFFICIENT && self.slope1 <= MAX_COEFFICIENT && self.slope2 <= MAX_COEFFICIENT,
+            "the base rate and slopes must not exceed 10!"
+        );
+    }
+
+    pub fn get_rate(&self, borrow_ratio: Decimal) -> Decimal{
+        let r = if borrow_ratio > Decimal::ONE { Decimal::ONE } else { borrow_ratio };
+        if r <= self.optimal_utilization {
+            self.base_rate.checked_add(
+                self.slope1.checked_mul(r).unwrap().checked_div(self.optimal_utilization).unwrap()
+            ).unwrap()
+        }
+        else{
+            let excess = r.checked_sub(self.optimal_utilization).unwrap()
+                .checked_div(Decimal::ONE.checked_sub(self.optimal_utilization).unwrap()).unwrap();
+            self.base_rate.checked_add(self.slope1).unwrap()
+                .checked_add(self.slope2.checked_mul(excess).unwrap()).unwrap()
+        }
+    }
 }
 
 
 #[blueprint]
 mod interest_model{
+
+    enable_method_auth!{
+        roles{
+            admin => updatable_by: [];
+        },
+        methods {
+            //admin
+            set_curve => restrict_to: [admin, OWNER];
+            set_coefficient => restrict_to: [admin, OWNER];
+            set_timelock => restrict_to: [admin, OWNER];
+            cancel_pending_curve => restrict_to: [admin, OWNER];
+            set_stable_premium => restrict_to: [admin, OWNER];
+
+            //public
+            apply_pending_curve => PUBLIC;
+
+            //readonly
+            get_curve => PUBLIC;
+            get_pending_curve => PUBLIC;
+            get_timelock => PUBLIC;
+            get_pending_timelock => PUBLIC;
+            get_stable_premium => PUBLIC;
+            get_pending_stable_premium => PUBLIC;
+            get_variable_interest_rate => PUBLIC;
+            get_stable_interest_rate => PUBLIC;
+        }
+    }
     
     struct DefInterestModel{
         validator_keeper: Global<AnyComponent>,
-        def_primary: Decimal,
-        def_quadratic: Decimal,
-        stable_coin_primary: Decimal,
-        stable_coin_quadratic: Decimal
+        // parameter set name => curve, the built-in models are kept under their curve names.
+        curve_map: HashMap<String, InterestCurve>,
+        // changes wait this many epochs before taking effect, zero applies them at once.
+        timelock_epochs: u64,
+        pending_timelock: Option<PendingTimelock>,
+        pending_curve_map: HashMap<String, PendingCurve>,
+        stable_premium: StablePremium,
+        pending_stable_premium: Option<PendingStablePremium>
     }
     
 
     impl DefInterestModel {
 
-        pub fn new(keeper_cmp_addr: ComponentAddress, def_primary: Decimal, def_quadratic: Decimal, stable_coin_primary: Decimal, stable_coin_quadratic:Decimal) -> Global<DefInterestModel>{
-            Self{
+        pub fn new(keeper_cmp_addr: ComponentAddress, def_primary: Decimal, def_quadratic: Decimal, stable_coin_primary: Decimal, stable_coin_quadratic:Decimal, kinked: KinkedParams) -> (Global<DefInterestModel>, Bucket){
+            let admin_badge = ResourceBuilder::new_fungible(OwnerRole::None)
+                //set divisibility to none to ensure that the admin badge can not be fractionalized.
+                .divisibility(DIVISIBILITY_NONE)
+                .mint_initial_supply(Decimal::ONE);
+
+            let curves = [
+                (DEFAULT_CURVE, InterestCurve::Quadratic{ primary: def_primary, quadratic: def_quadratic }),
+                (STABLE_COIN_CURVE, InterestCurve::StableCoin{ primary: stable_coin_primary, quadratic: stable_coin_quadratic }),
+                (KINKED_CURVE, InterestCurve::Kinked(kinked))
+            ];
+            let mut curve_map = HashMap::new();
+            for (name, curve) in curves {
+                curve.validate();
+                curve_map.insert(name.to_owned(), curve);
+            }
+
+            let admin_rule = rule!(require(admin_badge.resource_address()));
+            let component = Self{
                 validator_keeper: Global::from(keeper_cmp_addr),
-                def_primary,
-                def_quadratic,
-                stable_coin_primary,
-                stable_coin_quadratic
-            }.instantiate().prepare_to_globalize(OwnerRole::None).globalize()
+                curve_map,
+                timelock_epochs: 0u64,
+                pending_timelock: None,
+                pending_curve_map: HashMap::new(),
+                // no premium until the admin sets one.
+                stable_premium: StablePremium{
+                    stable_ratio_slope: Decimal::ZERO,
+                    utilization_threshold: Decimal::ZERO,
+                    utilization_slope: Decimal::ZERO
+                },
+                pending_stable_premium: None
+            }.instantiate()
+            .prepare_to_globalize(OwnerRole::Fixed(admin_rule.clone()))
+            .roles(
+                roles!(
+                    admin => admin_rule;
+                )
+            ).globalize();
+            (component, admin_badge.into())
         }
 
-        pub fn get_variable_interest_rate(&self, borrow_ratio: Decimal, model: InterestModel) -> Decimal{
-            match model{
-                InterestModel::Default => if borrow_ratio > Decimal::ONE {
-                    // dec!("0.2") + dec!("0.5")
-                    self.def_primary + self.def_quadratic
+        /// Adds the named parameter set, or replaces it once the timelock passes.
+        /// Pools of the model pick it up on their next accrual after that.
+        pub fn set_curve(&mut self, name: String, curve: InterestCurve){
+            curve.validate();
+            self.apply_pending_curve(name.clone());
+            let timelock_epochs = self.get_timelock();
+            if timelock_epochs == 0u64 {
+                self.curve_map.insert(name, curve);
+            }
+            else{
+                self.pending_curve_map.insert(name, PendingCurve{
+                    curve,
+                    effective_epoch: Runtime::current_epoch().number() + timelock_epochs
+                });
+            }
+        }
+
+        /// Changes one coefficient of the named curve, on top of the change pending for it if any.
+        pub fn set_coefficient(&mut self, name: String, coefficient: CurveCoefficient, value: Decimal){
+            let latest = match self.pending_curve_map.get(&name) {
+                Some(pending) => pending.curve.clone(),
+                None => self.get_curve(InterestModel::Named(name.clone()))
+            };
+            self.set_curve(name, latest.with_coefficient(coefficient, value));
+        }
+
+        /// Raising the timelock applies at once, lowering it waits for the current timelock,
+        /// otherwise the admin could lower it and rush a curve change through.
+        pub fn set_timelock(&mut self, timelock_epochs: u64){
+            let current_timelock = self.get_timelock();
+            self.timelock_epochs = current_timelock;
+            self.pending_timelock = None;
+            if timelock_epochs >= current_timelock {
+                self.timelock_epochs = timelock_epochs;
+            }
+            else{
+                self.pending_timelock = Some(PendingTimelock{
+                    timelock_epochs,
+                    effective_epoch: Runtime::current_epoch().number() + current_timelock
+                });
+            }
+        }
+
+        pub fn cancel_pending_curve(&mut self, name: String){
+            assert!(self.pending_curve_map.contains_key(&name), "there is no pending change of the curve!");
+            self.pending_curve_map.remove(&name);
+        }
+
+        /// Moves a pending change whose timelock has passed into the curves. Reading the rates
+        /// already honours such a change, so this only tidies up the state.
+        pub fn apply_pending_curve(&mut self, name: String){
+            if let Some(pending) = self.pending_curve_map.get(&name) {
+                if Runtime::current_epoch().number() >= pending.effective_epoch {
+                    let pending = self.pending_curve_map.remove(&name).unwrap();
+                    self.curve_map.insert(name, pending.curve);
                 }
-                else{
-                    // 0.2 * r + 0.5 * r**2
-                    borrow_ratio.checked_mul(self.def_primary).unwrap()
-                    .checked_add(borrow_ratio.checked_powi(2).unwrap().checked_mul(self.def_quadratic).unwrap()).unwrap()
-                },
-                InterestModel::StableCoin => {
-                    let r2 = if borrow_ratio > Decimal::ONE { Decimal::ONE} else{ borrow_ratio.checked_powi(2).unwrap()};
-                    let r4 = r2.checked_powi(2).unwrap();
-                    let r8 = r2.checked_powi(4).unwrap();
-                    // dec!("0.55") * x4  + dec!("0.45")* x8
-                    self.stable_coin_primary.checked_mul(r4).unwrap().checked_add(self.stable_coin_quadratic.checked_mul(r8).unwrap()).unwrap()
+            }
+        }
+
+        pub fn get_pending_curve(&self, name: String) -> Option<PendingCurve>{
+            self.pending_curve_map.get(&name).cloned()
+        }
+
+        pub fn get_timelock(&self) -> u64{
+            match self.pending_timelock {
+                Some(pending) if Runtime::current_epoch().number() >= pending.effective_epoch => pending.timelock_epochs,
+                _ => self.timelock_epochs
+            }
+        }
+
+        pub fn get_pending_timelock(&self) -> Option<PendingTimelock>{
+            self.pending_timelock
+        }
+
+        /// Replaces the premium once the timelock passes, like a curve change.
+        pub fn set_stable_premium(&mut self, stable_premium: StablePremium){
+            stable_premium.validate();
+            self.stable_premium = self.get_stable_premium();
+            self.pending_stable_premium = None;
+            let timelock_epochs = self.get_timelock();
+            if timelock_epochs == 0u64 {
+                self.stable_premium = stable_premium;
+            }
+            else{
+                self.pending_stable_premium = Some(PendingStablePremium{
+                    stable_premium,
+                    effective_epoch: Runtime::current_epoch().number() + timelock_epochs
+                });
+            }
+        }
+
+        pub fn get_stable_premium(&self) -> StablePremium{
+            match self.pending_stable_premium {
+                Some(pending) if Runtime::current_epoch().number() >= pending.effective_epoch => pending.stable_premium,
+                _ => self.stable_premium
+            }
+        }
+
+        pub fn get_pending_stable_premium(&self) -> Option<PendingStablePremium>{
+            self.pending_stable_premium
+        }
+
+        pub fn get_curve(&self, model: InterestModel) -> InterestCurve{
+            let name = model.curve_name();
+            if let Some(pending) = self.pending_curve_map.get(&name) {
+                if Runtime::current_epoch().number() >= pending.effective_epoch {
+                    return pending.curve.clone();
                 }
             }
+            assert!(self.curve_map.contains_key(&name), "there is no interest curve of the name!");
+            self.curve_map.get(&name).unwrap().clone()
+        }
+
+        pub fn get_variable_interest_rate(&self, borrow_ratio: Decimal, model: InterestModel) -> Decimal{
+            self.get_curve(model).get_rate(borrow_ratio)
         }
 
+        /// The greater of the variable rate and the validator APY, plus the stable premium.
         pub fn get_stable_interest_rate(&self, borrow_ratio: Decimal, stable_ratio: Decimal, model: InterestModel) -> Decimal{
             let apy = self.get_variable_interest_rate(borrow_ratio, model);
             let validator_apy = self.validator_keeper
                 .call_raw::<Decimal>("get_active_set_apy", scrypto_args!());
-            if apy > validator_apy {apy} else {validator_apy}
+            let base_rate = if apy > validator_apy {apy} else {validator_apy};
+            base_rate.checked_add(self.get_stable_premium().get_premium(borrow_ratio, stable_ratio)).unwrap()
+        }
+    }
+
+
+}
+
+/// The rate moves towards the target utilization: every epoch it steps by gain * (borrow_ratio - target_utilization),
+/// bounded by max_step, and stays in [min_rate, max_rate].
+#[derive(ScryptoSbor, Eq, PartialEq, Debug, Clone, Copy)]
+pub struct AdaptiveParams {
+    pub target_utilization: Decimal,
+    pub gain: Decimal,
+    pub min_rate: Decimal,
+    pub max_rate: Decimal,
+    pub max_step: Decimal
+}
+
+impl AdaptiveParams {
+    pub fn validate(&self){
+        assert!(
+            self.target_utilization > Decimal::ZERO && self.target_utilization < Decimal::ONE,
+            "the target utilization must be in (0, 1)!"
+        );
+        assert!(self.gain >= Decimal::ZERO && self.max_step > Decimal::ZERO, "the gain must not be negative and the max step must be positive!");
+        assert!(
+            self.min_rate >= Decimal::ZERO && self.min_rate <= self.max_rate && self.max_rate <= MAX_COEFFICIENT,
+            "the rates must satisfy 0 <= min rate <= max rate <= 10!"
+        );
+    }
+}
+
+#[derive(ScryptoSbor, Eq, PartialEq, Debug, Clone, Copy)]
+pub struct AdaptiveState {
+    pub params: AdaptiveParams,
+    pub rate: Decimal,
+    // the borrow ratio reported last, it drives the rate until the next report.
+    pub borrow_ratio: Decimal,
+    pub last_epoch: u64
+}
+
+impl AdaptiveState {
+    pub fn get_rate_at(&self, epoch_at: u64) -> Decimal{
+        let delta_epoch = epoch_at - self.last_epoch;
+        if delta_epoch == 0u64 {
+            return self.rate;
+        }
+        let params = &self.params;
+        let mut step = params.gain.checked_mul(self.borrow_ratio.checked_sub(params.target_utilization).unwrap()).unwrap();
+        if step > params.max_step {
+            step = params.max_step;
+        }
+        else if step < -params.max_step {
+            step = -params.max_step;
         }
+        let rate = self.rate.checked_add(step.checked_mul(Decimal::from(delta_epoch)).unwrap()).unwrap();
+        if rate < params.min_rate { params.min_rate } else if rate > params.max_rate { params.max_rate } else { rate }
     }
+}
+
+
+#[blueprint]
+mod adaptive_interest_model{
+
+    enable_method_auth!{
+        roles{
+            admin => updatable_by: [];
+        },
+        methods {
+            //admin
+            set_adaptive_params => restrict_to: [admin, OWNER];
+            set_reporter => restrict_to: [admin, OWNER];
+
+            //pool, checked against the reporter of the state.
+            report_borrow_ratio => PUBLIC;
+
+            //readonly
+            get_variable_interest_rate => PUBLIC;
+            get_stable_interest_rate => PUBLIC;
+            get_state => PUBLIC;
+            get_current_rate => PUBLIC;
+        }
+    }
+
+    struct AdaptiveInterestModel{
+        // parameter set name => state, each pool refers to its own entry by `InterestModel::Adaptive`.
+        state_map: HashMap<String, AdaptiveState>,
+        // parameter set name => the rule of the one pool reporting to the state.
+        reporter_map: HashMap<String, AccessRule>,
+        // added to the variable rate for stable loans.
+        stable_spread: Decimal
+    }
+
+    impl AdaptiveInterestModel {
+
+        pub fn instantiate(stable_spread: Decimal) -> (Global<AdaptiveInterestModel>, Bucket){
+            assert!(stable_spread >= Decimal::ZERO && stable_spread <= MAX_COEFFICIENT, "the stable spread must be in [0, 10]!");
+            let admin_badge = ResourceBuilder::new_fungible(OwnerRole::None)
+                //set divisibility to none to ensure that the admin badge can not be fractionalized.
+                .divisibility(DIVISIBILITY_NONE)
+                .mint_initial_supply(Decimal::ONE);
+
+            let admin_rule = rule!(require(admin_badge.resource_address()));
+            let component = Self{
+                state_map: HashMap::new(),
+                reporter_map: HashMap::new(),
+                stable_spread
+            }.instantiate()
+            .prepare_to_globalize(OwnerRole::Fixed(admin_rule.clone()))
+            .roles(
+                roles!(
+                    admin => admin_rule;
+                )
+            ).globalize();
+            (component, admin_badge.into())
+        }
 
+        /// A new entry starts at `initial_rate`, an existing one keeps its rate within the new bounds.
+        pub fn set_adaptive_params(&mut self, name: String, params: AdaptiveParams, initial_rate: Decimal){
+            params.validate();
+            let current_epoch = Runtime::current_epoch().number();
+            let state = match self.state_map.get(&name) {
+                Some(state) => {
+                    let rate = state.get_rate_at(current_epoch);
+                    AdaptiveState{
+                        params,
+                        rate: if rate < params.min_rate { params.min_rate } else if rate > params.max_rate { params.max_rate } else { rate },
+                        borrow_ratio: state.borrow_ratio,
+                        last_epoch: current_epoch
+                    }
+                },
+                None => {
+                    assert!(initial_rate >= params.min_rate && initial_rate <= params.max_rate, "the initial rate must be in [min rate, max rate]!");
+                    AdaptiveState{
+                        params,
+                        rate: initial_rate,
+                        borrow_ratio: params.target_utilization,
+                        last_epoch: current_epoch
+                    }
+                }
+            };
+            self.state_map.insert(name, state);
+        }
 
-}
\ No newline at end of file
+        /// Only the reporter may report to the state of the name, typically `rule!(require(global_caller(pool_addr)))`
+        /// so that a pool can not steer the rate of another.
+        pub fn set_reporter(&mut self, name: String, reporter_rule: AccessRule){
+            assert!(self.state_map.contains_key(&name), "there is no adaptive state of the name!");
+            self.reporter_map.insert(name, reporter_rule);
+        }
+
+        /// Settles the rate up to the current epoch with the borrow ratio reported last, then records the new one.
+        /// The pools report on every change of their balances, before they read the rates.
+        pub fn report_borrow_ratio(&mut self, borrow_ratio: Decimal, model: InterestModel){
+            let name = model.curve_name();
+            assert!(self.state_map.contains_key(&name), "there is no adaptive state of the name!");
+            assert!(self.reporter_map.contains_key(&name), "there is no reporter of the name!");
+            Runtime::assert_access_rule(self.reporter_map.get(&name).unwrap().clone());
+            let current_epoch = Runtime::current_epoch().number();
+            let state = self.state_map.get_mut(&name).unwrap();
+            state.rate = state.get_rate_at(current_epoch);
+            state.last_epoch = current_epoch;
+            state.borrow_ratio = borrow_ratio;
+        }
+
+        /// The rate at the current epoch, driven by the borrow ratio reported last rather than the one passed in.
+        pub fn get_variable_interest_rate(&self, _borrow_ratio: Decimal, model: InterestModel) -> Decimal{
+            self.get_current_rate(model.curve_name())
+        }
+
+        pub fn get_stable_interest_rate(&self, borrow_ratio: Decimal, _stable_ratio: Decimal, model: InterestModel) -> Decimal{
+            self.get_variable_interest_rate(borrow_ratio, model).checked_add(self.stable_spread).unwrap()
+        }
+
+        pub fn get_state(&self, name: String) -> AdaptiveState{
+            assert!(self.state_map.contains_key(&name), "there is no adaptive state of the name!");
+            *self.state_map.get(&name).unwrap()
+        }
+
+        pub fn get_current_rate(&self, name: String) -> Decimal{
+            self.get_state(name).get_rate_at(Runtime::current_epoch().number())
+        }
+    }
+}
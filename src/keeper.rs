@@ -1,4 +1,5 @@
 use scrypto::prelude::*;
+use crate::events::ValidatorSnapshotEvent;
 
 const EPOCH_OF_YEAR: u64 = 105120;
 const BABYLON_START_EPOCH: u64 = 32719;
@@ -13,6 +14,13 @@ pub struct StakeData{
 }
 
 
+/// Reported by the keeper admin, the fee factor of the validator and the share of its proposals made.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ScryptoSbor)]
+pub struct ValidatorPerformance{
+    pub fee: Decimal,
+    pub uptime: Decimal
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, ScryptoSbor, NonFungibleData)]
 pub struct UnstakeData {
     pub name: String,
@@ -27,6 +35,7 @@ pub struct UnstakeData {
 
 
 #[blueprint]
+#[events(ValidatorSnapshotEvent)]
 mod validator_keeper{
 
     enable_method_auth!{
@@ -36,15 +45,19 @@ mod validator_keeper{
         methods {
             //admin
             log_validator_staking => restrict_to: [admin, OWNER];
+            log_validator_performance => restrict_to: [admin, OWNER];
 
             //public
             get_active_set_apy => PUBLIC;
+            get_validator_stats => PUBLIC;
+            get_validator_performance => PUBLIC;
 
         }
     }
 
     struct ValidatorKeeper{
         validator_map: HashMap<ComponentAddress, Vec<StakeData>>,
+        performance_map: HashMap<ComponentAddress, ValidatorPerformance>,
         last_staked: Decimal,
         last_stake_epoch: u64
     }
@@ -58,6 +71,7 @@ mod validator_keeper{
 
             let component = Self{
                 validator_map: HashMap::new(),
+                performance_map: HashMap::new(),
                 last_staked: Decimal::ZERO,
                 last_stake_epoch: 0u64
             }.instantiate()
@@ -100,6 +114,7 @@ mod validator_keeper{
                     latest.last_staked = last_staked;
                     latest.last_stake_epoch = current_epoch;
                 }
+                Self::emit_snapshot(validator_addr, last_lsu, last_staked, current_epoch);
                 last_staked
             })
             .fold(Decimal::ZERO, |sum, staked| {
@@ -139,6 +154,7 @@ mod validator_keeper{
                 } 
 
             }).or_insert(Vec::from([Self::new_stake_data(last_lsu, last_staked, current_epoch)]));
+            Self::emit_snapshot(validator_addr, last_lsu, last_staked, current_epoch);
             
             last_staked
         }
@@ -151,6 +167,15 @@ mod validator_keeper{
             }
         }
 
+        fn emit_snapshot(validator_addr: &ComponentAddress, last_lsu: Decimal, last_staked: Decimal, epoch_at: u64){
+            Runtime::emit_event(ValidatorSnapshotEvent{
+                validator: validator_addr.clone(),
+                last_lsu,
+                last_staked,
+                epoch_at
+            });
+        }
+
         fn get_week_index(epoch_at: u64) -> usize{
             // let index: I192 = Decimal::from(epoch_at - BABYLON_START_EPOCH).checked_div(Decimal::from(A_WEEK_EPOCHS)).unwrap()
             // .checked_ceiling().unwrap().try_into();
@@ -186,6 +211,28 @@ mod validator_keeper{
         }
         
 
+        /// Every tracked validator with its APY of the current week, zero when unknown, and its latest staked XRD.
+        pub fn get_validator_stats(&self) -> Vec<(ComponentAddress, Decimal, Decimal)> {
+            let current_week_index = Self::get_week_index(Runtime::current_epoch().number());
+            self.validator_map.iter()
+                .map(|(validator_addr, vec)| {
+                    let apy = self.get_validator_apy(validator_addr, vec, current_week_index).unwrap_or(Decimal::ZERO);
+                    let staked = vec.first().map(|latest| latest.last_staked).unwrap_or(Decimal::ZERO);
+                    (validator_addr.clone(), apy, staked)
+                })
+                .collect()
+        }
+
+        pub fn log_validator_performance(&mut self, validator_addr: ComponentAddress, fee: Decimal, uptime: Decimal) {
+            assert!(fee >= Decimal::ZERO && fee <= Decimal::ONE, "the fee must be between 0 and 1!");
+            assert!(uptime >= Decimal::ZERO && uptime <= Decimal::ONE, "the uptime must be between 0 and 1!");
+            self.performance_map.insert(validator_addr, ValidatorPerformance{ fee, uptime });
+        }
+
+        pub fn get_validator_performance(&self, validator_addr: ComponentAddress) -> Option<ValidatorPerformance> {
+            self.performance_map.get(&validator_addr).copied()
+        }
+
         fn get_validator_apy(&self, _validator_addr: &ComponentAddress, queue: &Vec<StakeData>, current_week_index: usize) -> Option<Decimal> {
             let latest = queue.first()?;
             let latest_week_index = Self::get_week_index(latest.last_stake_epoch);
@@ -1,4 +1,8 @@
-mod pools;
+pub mod pools;
 mod keeper;
-mod utils;
-mod interest;
+pub mod utils;
+pub mod interest;
+mod cdp;
+mod oracle;
+mod controller;
+pub mod events;
@@ -0,0 +1,195 @@
+use scrypto::prelude::*;
+
+#[derive(ScryptoSbor, Eq, PartialEq, Debug, Clone, Copy)]
+pub struct PriceData {
+    pub price: Decimal,
+    pub epoch_at: u64
+}
+
+
+#[blueprint]
+mod price_oracle{
+
+    enable_method_auth!{
+        roles{
+            admin => updatable_by: [];
+            feeder => updatable_by: [admin];
+        },
+        methods {
+            //admin
+            set_price_feed => restrict_to: [admin, OWNER];
+            remove_price_feed => restrict_to: [admin, OWNER];
+            set_price_band => restrict_to: [admin, OWNER];
+
+            //feeder
+            set_price => restrict_to: [feeder, admin, OWNER];
+
+            //price reader, it records the feed prices for the band check.
+            get_price => PUBLIC;
+            get_price_data => PUBLIC;
+        }
+    }
+
+    struct PriceOracle{
+        // prices pushed by the feeder, all quoted in the same unit of account.
+        price_map: HashMap<ResourceAddress, PriceData>,
+        // external feeds take precedence over the pushed prices.
+        feed_map: HashMap<ResourceAddress, Global<AnyComponent>>,
+        // the last price read from the feed of the resource.
+        feed_price_map: HashMap<ResourceAddress, PriceData>,
+        // resource => (epoch the reference was taken at, the last price before that epoch). New prices
+        // are bounded by the reference, so the price moves at most max_deviation per epoch.
+        reference_map: HashMap<ResourceAddress, (u64, PriceData)>,
+        max_age_epochs: u64,
+        max_deviation: Decimal
+    }
+
+    impl PriceOracle {
+
+        /// The feeder rule may be a badge or a signature rule of the off-ledger signer,
+        /// e.g. `rule!(require(NonFungibleGlobalId::from_public_key(&public_key)))`.
+        pub fn instantiate(feeder_rule: AccessRule, max_age_epochs: u64, max_deviation: Decimal) -> (Global<PriceOracle>, Bucket){
+            assert!(max_deviation > Decimal::ZERO, "the max deviation must be positive!");
+            let admin_badge = ResourceBuilder::new_fungible(OwnerRole::None)
+                //set divisibility to none to ensure that the admin badge can not be fractionalized.
+                .divisibility(DIVISIBILITY_NONE)
+                .mint_initial_supply(Decimal::ONE);
+
+            let admin_rule = rule!(require(admin_badge.resource_address()));
+            let component = Self{
+                price_map: HashMap::new(),
+                feed_map: HashMap::new(),
+                feed_price_map: HashMap::new(),
+                reference_map: HashMap::new(),
+                max_age_epochs,
+                max_deviation
+            }.instantiate()
+            .prepare_to_globalize(OwnerRole::Fixed(admin_rule.clone()))
+            .roles(
+                roles!(
+                    admin => admin_rule;
+                    feeder => feeder_rule;
+                )
+            ).globalize();
+            (component, admin_badge.into())
+        }
+
+        pub fn set_price(&mut self, res_addr: ResourceAddress, price: Decimal){
+            assert!(price > Decimal::ZERO, "the price must be positive!");
+            let current_epoch = Runtime::current_epoch().number();
+            let last = self.price_map.get(&res_addr).copied();
+            self.check_band(res_addr, last, price);
+            self.price_map.insert(res_addr, PriceData{
+                price,
+                epoch_at: current_epoch
+            });
+        }
+
+        pub fn set_price_feed(&mut self, res_addr: ResourceAddress, feed_addr: ComponentAddress){
+            self.feed_map.insert(res_addr, Global::from(feed_addr));
+        }
+
+        pub fn remove_price_feed(&mut self, res_addr: ResourceAddress){
+            self.feed_map.remove(&res_addr);
+        }
+
+        pub fn set_price_band(&mut self, max_age_epochs: u64, max_deviation: Decimal){
+            assert!(max_deviation > Decimal::ZERO, "the max deviation must be positive!");
+            self.max_age_epochs = max_age_epochs;
+            self.max_deviation = max_deviation;
+        }
+
+        /// Returns how many quote tokens one base token is worth.
+        pub fn get_price(&mut self, base: ResourceAddress, quote: ResourceAddress) -> Decimal{
+            if base == quote {
+                return Decimal::ONE;
+            }
+            let base_price = self.get_price_data(base).price;
+            let quote_price = self.get_price_data(quote).price;
+            base_price.checked_div(quote_price).unwrap()
+        }
+
+        /// A feed price must be within the band too.
+        pub fn get_price_data(&mut self, res_addr: ResourceAddress) -> PriceData{
+            let price_data = match self.feed_map.get(&res_addr) {
+                Some(feed) => {
+                    let (price, epoch_at) = feed.call_raw::<(Decimal, u64)>("get_price", scrypto_args!(res_addr));
+                    let last = self.feed_price_map.get(&res_addr).copied();
+                    self.check_band(res_addr, last, price);
+                    let price_data = PriceData{ price, epoch_at };
+                    self.feed_price_map.insert(res_addr, price_data);
+                    price_data
+                },
+                None => {
+                    assert!(self.price_map.contains_key(&res_addr), "there is no price for the resource!");
+                    *self.price_map.get(&res_addr).unwrap()
+                }
+            };
+            assert!(price_data.price > Decimal::ZERO, "the price must be positive!");
+            let current_epoch = Runtime::current_epoch().number();
+            assert!(price_data.epoch_at <= current_epoch, "the price is from a future epoch!");
+            assert!(
+                current_epoch - price_data.epoch_at <= self.max_age_epochs,
+                "the price is stale!"
+            );
+            price_data
+        }
+
+        /// The reference is taken once per epoch from the last price. Without a fresh one the price
+        /// itself becomes the reference of the epoch.
+        fn check_band(&mut self, res_addr: ResourceAddress, last: Option<PriceData>, price: Decimal){
+            let current_epoch = Runtime::current_epoch().number();
+            let reference = match self.reference_map.get(&res_addr) {
+                Some((taken_at, reference)) if *taken_at == current_epoch => Some(*reference),
+                _ => last
+            }.filter(|reference| reference.epoch_at <= current_epoch && current_epoch - reference.epoch_at <= self.max_age_epochs);
+
+            match reference {
+                Some(reference) => {
+                    let delta = if price > reference.price { price - reference.price } else { reference.price - price };
+                    assert!(delta.checked_div(reference.price).unwrap() <= self.max_deviation, "the price is out of band!");
+                    self.reference_map.insert(res_addr, (current_epoch, reference));
+                },
+                None => {
+                    self.reference_map.insert(res_addr, (current_epoch, PriceData{ price, epoch_at: current_epoch }));
+                }
+            }
+        }
+    }
+}
+
+
+/// A feed whose prices can be set by anyone, for exercising the oracle in tests.
+/// It is only built with the `test` feature, so it never ships in the package.
+#[cfg(feature = "test")]
+#[blueprint]
+mod mock_price_feed{
+
+    struct MockPriceFeed{
+        price_map: HashMap<ResourceAddress, PriceData>
+    }
+
+    impl MockPriceFeed {
+
+        pub fn instantiate() -> Global<MockPriceFeed>{
+            Self{
+                price_map: HashMap::new()
+            }.instantiate().prepare_to_globalize(OwnerRole::None).globalize()
+        }
+
+        pub fn set_price(&mut self, res_addr: ResourceAddress, price: Decimal){
+            let epoch_at = Runtime::current_epoch().number();
+            self.set_price_at(res_addr, price, epoch_at);
+        }
+
+        pub fn set_price_at(&mut self, res_addr: ResourceAddress, price: Decimal, epoch_at: u64){
+            self.price_map.insert(res_addr, PriceData{ price, epoch_at });
+        }
+
+        pub fn get_price(&self, res_addr: ResourceAddress) -> (Decimal, u64){
+            assert!(self.price_map.contains_key(&res_addr), "there is no price for the resource!");
+            let price_data = self.price_map.get(&res_addr).unwrap();
+            (price_data.price, price_data.epoch_at)
+        }
+    }
+}
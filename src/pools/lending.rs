@@ -1,10 +1,30 @@
 use scrypto::prelude::*;
 use crate::utils::*;
 use crate::interest::InterestModel;
-
-const EPOCH_OF_YEAR: u64 = 15017;
+use crate::events::*;
+
+pub const EPOCH_OF_YEAR: u64 = 15017;
+
+/// Receipt of a stable rate loan, it is updated on every repayment and burnt once the loan is repaid.
+#[derive(ScryptoSbor, NonFungibleData)]
+pub struct StableLoanData {
+    #[mutable]
+    pub loan_amount: Decimal,
+    #[mutable]
+    pub rate: Decimal,
+    #[mutable]
+    pub last_epoch_at: u64
+}
+
+/// Receipt of a flash loan, it can not be deposited so it must be repaid in the same transaction.
+#[derive(ScryptoSbor, NonFungibleData)]
+pub struct FlashLoanData {
+    pub amount: Decimal,
+    pub fee: Decimal
+}
 
 #[blueprint]
+#[events(DepositEvent, WithdrawEvent, BorrowEvent, RepayEvent, RateUpdateEvent, SwapRateModeEvent, StableRateRebalanceEvent, FlashBorrowEvent, FlashRepayEvent)]
 mod lend_pool {
 
     enable_method_auth!{
@@ -13,7 +33,14 @@ mod lend_pool {
         },
         methods {
             // new_pool => restrict_to: [admin, OWNER];
-            // withdraw_fee => restrict_to: [admin, OWNER];  // withdraw_fee should restrict to Pool?
+            withdraw_insurance => restrict_to: [pool_owner];
+            set_insurance_ratio => restrict_to: [pool_owner];
+            set_caps => restrict_to: [pool_owner];
+            set_flash_loan_fee_ratio => restrict_to: [pool_owner];
+            set_rebalance_params => restrict_to: [pool_owner];
+
+            // public
+            accrue_interest => PUBLIC;
 
             // readonly
             get_current_index => PUBLIC;
@@ -25,14 +52,27 @@ mod lend_pool {
             get_last_update => PUBLIC;
             get_redemption_value => PUBLIC;
             get_underlying_value => PUBLIC;
+            get_caps => PUBLIC;
+            get_insurance => PUBLIC;
+            get_stable_loan => PUBLIC;
+            get_stable_loan_debt => PUBLIC;
+            get_stable_loan_token => PUBLIC;
+            get_stable_loan_total => PUBLIC;
+            get_flash_loan_token => PUBLIC;
 
             //business method
             add_liquity => PUBLIC;
             remove_liquity => PUBLIC;
-            borrow_variable => PUBLIC;
-            borrow_stable => PUBLIC;
-            repay_stable => PUBLIC;
-            repay_variable => PUBLIC;
+            borrow_variable => restrict_to: [pool_owner];
+            borrow_stable => restrict_to: [pool_owner];
+            repay_stable => restrict_to: [pool_owner];
+            repay_variable => restrict_to: [pool_owner];
+            swap_to_stable => restrict_to: [pool_owner];
+            swap_to_variable => restrict_to: [pool_owner];
+            rebalance_stable_rate => PUBLIC;
+            flash_borrow => PUBLIC;
+            flash_repay => PUBLIC;
+            add_insurance => restrict_to: [pool_owner];
         }
     }
     
@@ -61,7 +101,19 @@ mod lend_pool {
         
         stable_loan_interest_rate: Decimal,
         stable_loan_amount: Decimal,
-        stable_loan_last_update: u64
+        stable_loan_last_update: u64,
+
+        supply_cap: Decimal,
+        borrow_cap: Decimal,
+
+        stable_loan_res_mgr: ResourceManager,
+        // a stable loan may be rebalanced to the current stable rate once the borrow ratio reaches
+        // rebalance_utilization, or the deposit rate reaches rebalance_deposit_ratio of the stable rate.
+        rebalance_utilization: Decimal,
+        rebalance_deposit_ratio: Decimal,
+
+        flash_loan_res_mgr: ResourceManager,
+        flash_loan_fee_ratio: Decimal
     }
 
 
@@ -81,8 +133,13 @@ mod lend_pool {
             let res_mgr = ResourceManager::from_address(underlying_token);
             let origin_symbol: String = res_mgr.get_metadata::<&str, String>("symbol").unwrap().unwrap();
 
-            let (address_reservation, address) =
-                Runtime::allocate_component_address(LendResourcePool::blueprint_id());
+            let (address_reservation, address) = match address_reservation {
+                Some(address_reservation) => {
+                    let address = ComponentAddress::try_from(Runtime::get_reservation_address(&address_reservation)).unwrap();
+                    (address_reservation, address)
+                },
+                None => Runtime::allocate_component_address(LendResourcePool::blueprint_id())
+            };
 
             let deposit_share_res_mgr = ResourceBuilder::new_fungible(owner_role.clone())
                 .metadata(metadata!(init{
@@ -91,13 +148,51 @@ mod lend_pool {
                     "name" => format!("DeXian Staking Earning LP token({}) ", origin_symbol), locked;
                 }))
                 .mint_roles(mint_roles! {
-                    minter => pool_mgr_rule.clone();
+                    minter => rule!(require(global_caller(address)));
+                    minter_updater => rule!(deny_all);
+                })
+                .burn_roles(burn_roles! {
+                    burner => rule!(require(global_caller(address)));
+                    burner_updater => rule!(deny_all);
+                })
+                .create_with_no_initial_supply();
+
+            let stable_loan_res_mgr = ResourceBuilder::new_ruid_non_fungible::<StableLoanData>(OwnerRole::None)
+                .metadata(metadata!(init{
+                    "pool" => address, locked;
+                    "name" => format!("DeXian Stable Loan Receipt({}) ", origin_symbol), locked;
+                }))
+                .mint_roles(mint_roles! {
+                    minter => rule!(require(global_caller(address)));
+                    minter_updater => rule!(deny_all);
+                })
+                .burn_roles(burn_roles! {
+                    burner => rule!(require(global_caller(address)));
+                    burner_updater => rule!(deny_all);
+                })
+                .non_fungible_data_update_roles(non_fungible_data_update_roles! {
+                    non_fungible_data_updater => rule!(require(global_caller(address)));
+                    non_fungible_data_updater_updater => rule!(deny_all);
+                })
+                .create_with_no_initial_supply();
+
+            let flash_loan_res_mgr = ResourceBuilder::new_ruid_non_fungible::<FlashLoanData>(OwnerRole::None)
+                .metadata(metadata!(init{
+                    "pool" => address, locked;
+                    "name" => format!("DeXian Flash Loan Receipt({}) ", origin_symbol), locked;
+                }))
+                .mint_roles(mint_roles! {
+                    minter => rule!(require(global_caller(address)));
                     minter_updater => rule!(deny_all);
                 })
                 .burn_roles(burn_roles! {
-                    burner => pool_mgr_rule.clone();
+                    burner => rule!(require(global_caller(address)));
                     burner_updater => rule!(deny_all);
                 })
+                .deposit_roles(deposit_roles! {
+                    depositor => rule!(deny_all);
+                    depositor_updater => rule!(deny_all);
+                })
                 .create_with_no_initial_supply();
 
             let deposit_share_addr = deposit_share_res_mgr.address();
@@ -106,20 +201,32 @@ mod lend_pool {
                 deposit_share_token: deposit_share_addr,
                 deposit_index: Decimal::ONE,
                 loan_index: Decimal::ONE,
-                last_update: 0u64,
+                last_update: Runtime::current_epoch().number(),
                 deposit_interest_rate: Decimal::ZERO,
                 variable_loan_interest_rate: Decimal::ZERO,
                 variable_loan_share_quantity: Decimal::ZERO,
                 stable_loan_interest_rate: Decimal::ZERO,
                 stable_loan_amount: Decimal::ZERO,
-                stable_loan_last_update: 0u64,
+                stable_loan_last_update: Runtime::current_epoch().number(),
                 vault: Vault::new(underlying_token),
                 insurance_balance: Decimal::ZERO,
+                supply_cap: Decimal::MAX,
+                borrow_cap: Decimal::MAX,
+                stable_loan_res_mgr,
+                rebalance_utilization: dec!("0.95"),
+                rebalance_deposit_ratio: dec!("0.4"),
+                flash_loan_res_mgr,
+                flash_loan_fee_ratio: Decimal::ZERO,
                 interest_model,
                 insurance_ratio,
                 underlying_token
             }.instantiate()
             .prepare_to_globalize(owner_role)
+            .roles(
+                roles!(
+                    pool_owner => pool_mgr_rule.clone();
+                )
+            )
             .with_address(address_reservation)
             .globalize();
             
@@ -128,25 +235,71 @@ mod lend_pool {
         }
 
         pub fn get_underlying_value(&self) -> Decimal{
-            let res_mgr = ResourceManager::from_address(self.underlying_token);
             let (supply_index, _) = self.get_current_index();
-            res_mgr.total_supply().unwrap().checked_mul(supply_index).unwrap()
+            self.get_deposit_share_quantity().checked_mul(supply_index).unwrap()
+        }
+
+        pub fn set_caps(&mut self, supply_cap: Decimal, borrow_cap: Decimal){
+            assert!(supply_cap >= Decimal::ZERO && borrow_cap >= Decimal::ZERO, "the caps must not be negative!");
+            self.supply_cap = supply_cap;
+            self.borrow_cap = borrow_cap;
+        }
+
+        pub fn get_caps(&self) -> (Decimal, Decimal){
+            (self.supply_cap, self.borrow_cap)
+        }
+
+        pub fn withdraw_insurance(&mut self, amount: Decimal) -> Bucket{
+            self.update_index();
+            assert!(amount > Decimal::ZERO && amount <= self.insurance_balance, "the insurance balance is insufficient.");
+            assert_vault_amount(&self.vault, amount);
+            self.insurance_balance = self.insurance_balance.checked_sub(amount).unwrap();
+            self.vault.take(amount)
+        }
+
+        pub fn set_insurance_ratio(&mut self, insurance_ratio: Decimal){
+            assert!(insurance_ratio >= Decimal::ZERO && insurance_ratio < Decimal::ONE, "the insurance ratio must be in [0, 1)!");
+            // settle the interest at the old ratio, so the new one only applies from now on.
+            self.update_index();
+            self.insurance_ratio = insurance_ratio;
+            self.update_interest_rate();
+        }
+
+        pub fn get_insurance(&self) -> (Decimal, Decimal){
+            (self.insurance_balance, self.insurance_ratio)
+        }
+
+        pub fn set_flash_loan_fee_ratio(&mut self, flash_loan_fee_ratio: Decimal){
+            assert!(flash_loan_fee_ratio >= Decimal::ZERO && flash_loan_fee_ratio < Decimal::ONE, "the flash loan fee ratio must be in [0, 1)!");
+            self.flash_loan_fee_ratio = flash_loan_fee_ratio;
         }
 
         pub fn add_liquity(&mut self, bucket: Bucket) -> Bucket{
             assert_resource(&bucket.resource_address(), &self.underlying_token);
+            self.update_index();
             let deposit_amount = bucket.amount();
+            assert!(
+                self.get_underlying_value().checked_add(deposit_amount).unwrap() <= self.supply_cap,
+                "the supply cap of the pool is exceeded!"
+            );
             let mint_amount = floor(deposit_amount.checked_div(self.deposit_index).unwrap());
+            self.vault.put(bucket);
             let deposit_share_res_mgr = ResourceManager::from_address(self.deposit_share_token);
-            let bucket = deposit_share_res_mgr.mint(mint_amount);
+            let share_bucket = deposit_share_res_mgr.mint(mint_amount);
             
             self.update_interest_rate();
+
+            Runtime::emit_event(DepositEvent{
+                amount: deposit_amount,
+                share_amount: mint_amount
+            });
             
-            bucket
+            share_bucket
 
         }
         pub fn remove_liquity(&mut self, bucket: Bucket) -> Bucket{
             assert_resource(&bucket.resource_address(), &self.deposit_share_token);
+            self.update_index();
             let burn_amount = bucket.amount();
             let withdraw_amount = self.get_redemption_value(burn_amount);
             assert_vault_amount(&self.vault, withdraw_amount);
@@ -155,6 +308,11 @@ mod lend_pool {
             
             self.update_interest_rate();
 
+            Runtime::emit_event(WithdrawEvent{
+                amount: withdraw_amount,
+                share_amount: burn_amount
+            });
+
             self.vault.take(withdraw_amount)
 
         }
@@ -165,94 +323,254 @@ mod lend_pool {
         //     withdraw_strategy: WithdrawStrategy,
         // ) -> Bucket;
 
-        pub fn borrow_variable(&mut self, borrow_amount: Decimal) -> Bucket{
+        pub fn borrow_variable(&mut self, borrow_amount: Decimal) -> (Bucket, Decimal){
             assert_vault_amount(&self.vault, borrow_amount);
+            self.update_index();
+            self.assert_borrow_cap(borrow_amount);
             let variable_share = borrow_amount.checked_div(self.loan_index).unwrap();
             self.variable_loan_share_quantity = self.variable_loan_share_quantity.checked_add(variable_share).unwrap();
             
             self.update_interest_rate();
+
+            Runtime::emit_event(BorrowEvent{
+                amount: borrow_amount,
+                stable: false,
+                rate: self.variable_loan_interest_rate
+            });
             
-            self.vault.take(borrow_amount)
+            (self.vault.take(borrow_amount), variable_share)
         }
 
-        pub fn borrow_stable(&mut self, borrow_amount: Decimal, stable_rate: Decimal) -> Bucket{
+        pub fn borrow_stable(&mut self, borrow_amount: Decimal) -> (Bucket, Bucket){
             assert_vault_amount(&self.vault, borrow_amount);
-            let new_amount = self.stable_loan_amount.checked_add(borrow_amount).unwrap();
-            self.stable_loan_interest_rate = self.stable_loan_amount.checked_mul(self.stable_loan_interest_rate).unwrap()
-                .checked_add(borrow_amount.checked_mul(stable_rate).unwrap()).unwrap()
-                .checked_div(new_amount).unwrap();
-            self.stable_loan_amount = new_amount;
+            self.update_index();
+            self.assert_borrow_cap(borrow_amount);
+            let (_, stable_rate, _) = self.get_interest_rate();
+            let loan_receipt = self.add_stable_loan(borrow_amount, stable_rate);
 
             self.update_interest_rate();
 
-            self.vault.take(borrow_amount)
+            Runtime::emit_event(BorrowEvent{
+                amount: borrow_amount,
+                stable: true,
+                rate: stable_rate
+            });
+
+            (self.vault.take(borrow_amount), loan_receipt)
 
         }
 
 
-        pub fn repay_variable(&mut self, repay_bucket: Bucket) -> Decimal{
+        /// Repays the variable loan of `loan_share` shares, anything above its debt is returned as change.
+        /// Returns the change and the shares burned.
+        pub fn repay_variable(&mut self, mut repay_bucket: Bucket, loan_share: Decimal) -> (Bucket, Decimal){
             assert_resource(&repay_bucket.resource_address(), &self.underlying_token);
-            let amount = repay_bucket.amount();
-            let loan_share = amount.checked_div(self.loan_index).unwrap();
+            assert!(
+                loan_share > Decimal::ZERO && loan_share <= self.variable_loan_share_quantity,
+                "the variable loan share is insufficient!"
+            );
+            self.update_index();
+            let debt = ceil(loan_share.checked_mul(self.loan_index).unwrap());
+            let (repay_amount, repay_share) = if repay_bucket.amount() >= debt {
+                (debt, loan_share)
+            }
+            else{
+                let amount = repay_bucket.amount();
+                (amount, amount.checked_div(self.loan_index).unwrap())
+            };
 
-            self.variable_loan_share_quantity = self.variable_loan_share_quantity.checked_sub(loan_share).unwrap();
+            self.variable_loan_share_quantity = self.variable_loan_share_quantity.checked_sub(repay_share).unwrap();
+            self.vault.put(repay_bucket.take(repay_amount));
             
             self.update_interest_rate();
+
+            Runtime::emit_event(RepayEvent{
+                amount: repay_amount,
+                stable: false
+            });
             
-            loan_share
-        }
-
-        pub fn repay_stable(
-            &mut self, 
-            mut repay_bucket: Bucket, 
-            loan_amount: Decimal,
-            rate: Decimal,
-            last_epoch_at: u64
-        ) -> (Decimal, Decimal, Decimal, u64){
-            let current_epoch_at = Runtime::current_epoch().number();
-            let interest = ceil(loan_amount 
-                .checked_mul(rate).unwrap()
-                .checked_mul(Decimal::from( current_epoch_at - last_epoch_at).checked_div(Decimal::from(EPOCH_OF_YEAR)).unwrap()).unwrap()
-                );
-            
-            let previous_debt = self.stable_loan_amount.checked_mul(self.stable_loan_interest_rate).unwrap();
+            (repay_bucket, repay_share)
+        }
 
-            let mut repay_amount = repay_bucket.amount();
-            let mut repay_in_borrow = Decimal::ZERO;
-            let mut normalized_amount = Decimal::ZERO; 
-            if repay_amount < interest {
-                let outstanding_interest = interest.checked_sub(repay_amount).unwrap();
-                repay_in_borrow = outstanding_interest * Decimal::from(-1);
-                self.stable_loan_amount = self.stable_loan_amount.checked_add(outstanding_interest).unwrap();
-                self.stable_loan_interest_rate = (previous_debt + outstanding_interest * rate) / self.stable_loan_amount;
+        pub fn repay_stable(&mut self, mut repay_bucket: Bucket, loan_receipt: Bucket) -> (Bucket, Option<Bucket>){
+            assert_resource(&repay_bucket.resource_address(), &self.underlying_token);
+            assert_resource(&loan_receipt.resource_address(), &self.stable_loan_res_mgr.address());
+            self.update_index();
+            let loan_id = loan_receipt.as_non_fungible().non_fungible_local_id();
+            let loan = self.get_stable_loan(loan_id.clone());
+            let debt = self.calc_stable_loan_debt(&loan);
+            let repay_amount = if repay_bucket.amount() > debt { debt } else { repay_bucket.amount() };
+
+            self.remove_stable_loan(repay_amount, loan.rate);
+            self.vault.put(repay_bucket.take(repay_amount));
+
+            let remain = debt.checked_sub(repay_amount).unwrap();
+            let loan_receipt = if remain == Decimal::ZERO {
+                loan_receipt.burn();
+                None
             }
             else{
-                if repay_amount >= loan_amount + interest {
-                    repay_amount = loan_amount + interest;
-                    repay_in_borrow = loan_amount;
-                }
-                else{
-                    repay_in_borrow = repay_amount - interest;
-                }
-                
-                // The final repayment may be greater than the total amount borrowed.
-                // This is because each loan repayment is calculated separately.
-                if repay_in_borrow >= self.stable_loan_amount{
-                    self.stable_loan_amount = Decimal::ZERO;
-                    self.stable_loan_interest_rate = Decimal::ZERO;
-                }
-                else{
-                    self.stable_loan_amount = self.stable_loan_amount.checked_sub(repay_in_borrow).unwrap();
-                    self.stable_loan_interest_rate = (previous_debt - repay_in_borrow * rate) /self.stable_loan_amount;
-                }
-            }
-            
+                // the unpaid interest is added to the principal.
+                self.stable_loan_res_mgr.update_non_fungible_data(&loan_id, "loan_amount", remain);
+                self.stable_loan_res_mgr.update_non_fungible_data(&loan_id, "last_epoch_at", Runtime::current_epoch().number());
+                Some(loan_receipt)
+            };
+
+            self.update_interest_rate();
+
+            Runtime::emit_event(RepayEvent{
+                amount: repay_amount,
+                stable: true
+            });
+
+            (repay_bucket, loan_receipt)
+
+        }
+
+        pub fn swap_to_stable(&mut self, variable_share: Decimal) -> Bucket{
+            assert!(
+                variable_share > Decimal::ZERO && variable_share <= self.variable_loan_share_quantity,
+                "the variable loan share is insufficient!"
+            );
+            self.update_index();
+            let debt = ceil(variable_share.checked_mul(self.loan_index).unwrap());
+            // the debt stays in the pool, so it is priced at the utilization including it.
+            let (_, stable_rate, _) = self.get_interest_rate();
+            self.variable_loan_share_quantity = self.variable_loan_share_quantity.checked_sub(variable_share).unwrap();
+            let loan_receipt = self.add_stable_loan(debt, stable_rate);
+
+            self.update_interest_rate();
+
+            Runtime::emit_event(SwapRateModeEvent{
+                amount: debt,
+                stable: true,
+                rate: stable_rate
+            });
+
+            loan_receipt
+        }
+
+        pub fn swap_to_variable(&mut self, loan_receipt: Bucket) -> Decimal{
+            assert_resource(&loan_receipt.resource_address(), &self.stable_loan_res_mgr.address());
+            self.update_index();
+            let loan = self.get_stable_loan(loan_receipt.as_non_fungible().non_fungible_local_id());
+            let debt = self.calc_stable_loan_debt(&loan);
+            self.remove_stable_loan(debt, loan.rate);
+            loan_receipt.burn();
+
+            let variable_share = debt.checked_div(self.loan_index).unwrap();
+            self.variable_loan_share_quantity = self.variable_loan_share_quantity.checked_add(variable_share).unwrap();
+
+            self.update_interest_rate();
+
+            Runtime::emit_event(SwapRateModeEvent{
+                amount: debt,
+                stable: false,
+                rate: self.variable_loan_interest_rate
+            });
+
+            variable_share
+        }
+
+        pub fn rebalance_stable_rate(&mut self, loan_id: NonFungibleLocalId){
+            self.update_index();
+            // the conditions must be checked against the rates of the current state.
+            self.update_interest_rate();
+            let loan = self.get_stable_loan(loan_id.clone());
+            let (_, stable_rate, _) = self.get_interest_rate();
+            assert!(stable_rate > loan.rate, "the rate of the loan is not below the current stable rate!");
+            assert!(
+                self.get_borrow_ratio() >= self.rebalance_utilization
+                || self.deposit_interest_rate >= stable_rate.checked_mul(self.rebalance_deposit_ratio).unwrap(),
+                "the pool does not meet the rebalance conditions!"
+            );
+
+            let debt = self.calc_stable_loan_debt(&loan);
+            let previous_debt = self.stable_loan_amount.checked_mul(self.stable_loan_interest_rate).unwrap();
+            self.stable_loan_interest_rate = previous_debt
+                .checked_add(debt.checked_mul(stable_rate.checked_sub(loan.rate).unwrap()).unwrap()).unwrap()
+                .checked_div(self.stable_loan_amount).unwrap();
+
+            self.stable_loan_res_mgr.update_non_fungible_data(&loan_id, "loan_amount", debt);
+            self.stable_loan_res_mgr.update_non_fungible_data(&loan_id, "rate", stable_rate);
+            self.stable_loan_res_mgr.update_non_fungible_data(&loan_id, "last_epoch_at", Runtime::current_epoch().number());
+
+            self.update_interest_rate();
+
+            Runtime::emit_event(StableRateRebalanceEvent{
+                loan_id,
+                debt,
+                previous_rate: loan.rate,
+                rate: stable_rate
+            });
+        }
+
+        pub fn set_rebalance_params(&mut self, rebalance_utilization: Decimal, rebalance_deposit_ratio: Decimal){
+            assert!(rebalance_utilization > Decimal::ZERO && rebalance_utilization <= Decimal::ONE, "the rebalance utilization must be in (0, 1]!");
+            assert!(rebalance_deposit_ratio > Decimal::ZERO && rebalance_deposit_ratio <= Decimal::ONE, "the rebalance deposit ratio must be in (0, 1]!");
+            self.rebalance_utilization = rebalance_utilization;
+            self.rebalance_deposit_ratio = rebalance_deposit_ratio;
+        }
+
+        pub fn flash_borrow(&mut self, amount: Decimal) -> (Bucket, Bucket){
+            assert_vault_amount(&self.vault, amount);
+            let fee = ceil(amount.checked_mul(self.flash_loan_fee_ratio).unwrap());
+            let receipt = self.flash_loan_res_mgr.mint_ruid_non_fungible(FlashLoanData{
+                amount,
+                fee
+            });
+
+            Runtime::emit_event(FlashBorrowEvent{
+                amount,
+                fee
+            });
+
+            (self.vault.take(amount), receipt)
+        }
+
+        pub fn flash_repay(&mut self, mut repay_bucket: Bucket, receipt: Bucket) -> Bucket{
+            assert_resource(&repay_bucket.resource_address(), &self.underlying_token);
+            assert_resource(&receipt.resource_address(), &self.flash_loan_res_mgr.address());
+            let flash_loan = receipt.as_non_fungible().non_fungible::<FlashLoanData>().data();
+            self.update_index();
+            let repay_amount = flash_loan.amount.checked_add(flash_loan.fee).unwrap();
+            assert!(repay_bucket.amount() >= repay_amount, "the flash loan is not fully repaid!");
+
             self.vault.put(repay_bucket.take(repay_amount));
+            receipt.burn();
+
+            // the insurance ratio of the fee goes to the insurance, the rest to the depositors.
+            let supply = self.get_deposit_share_quantity();
+            let insurance = if supply == Decimal::ZERO { flash_loan.fee } else { flash_loan.fee.checked_mul(self.insurance_ratio).unwrap() };
+            self.insurance_balance = self.insurance_balance.checked_add(insurance).unwrap();
+            if supply > Decimal::ZERO {
+                let deposit_fee = flash_loan.fee.checked_sub(insurance).unwrap();
+                self.deposit_index = self.deposit_index.checked_add(deposit_fee.checked_div(supply).unwrap()).unwrap();
+            }
 
             self.update_interest_rate();
 
-            (repay_amount, repay_in_borrow, interest, current_epoch_at)
+            Runtime::emit_event(FlashRepayEvent{
+                amount: flash_loan.amount,
+                fee: flash_loan.fee
+            });
 
+            repay_bucket
+        }
+
+        pub fn get_flash_loan_token(&self) -> ResourceAddress{
+            self.flash_loan_res_mgr.address()
+        }
+
+        pub fn add_insurance(&mut self, bucket: Bucket){
+            assert_resource(&bucket.resource_address(), &self.underlying_token);
+            self.insurance_balance = self.insurance_balance.checked_add(bucket.amount()).unwrap();
+            self.vault.put(bucket);
+        }
+
+        pub fn accrue_interest(&mut self){
+            self.update_index();
+            self.update_interest_rate();
         }
 
         pub fn get_current_index(&self) -> (Decimal, Decimal){
@@ -261,13 +579,9 @@ mod lend_pool {
                 return (self.deposit_index, self.loan_index);
             }
             
-            let delta_epoch_year = Decimal::from(delta_epoch) / Decimal::from(EPOCH_OF_YEAR);
-            let delta_borrow_interest_rate = self.variable_loan_interest_rate.checked_mul(delta_epoch_year).unwrap();
-            let delta_supply_interest_rate = self.deposit_interest_rate.checked_mul(delta_epoch_year).unwrap();
-
             (
-                self.deposit_index.checked_mul(Decimal::ONE.checked_add(delta_supply_interest_rate).unwrap()).unwrap(),
-                self.loan_index.checked_mul(Decimal::ONE.checked_add(delta_borrow_interest_rate).unwrap()).unwrap()
+                self.deposit_index.checked_mul(calc_compound_growth(self.deposit_interest_rate, delta_epoch, EPOCH_OF_YEAR)).unwrap(),
+                self.loan_index.checked_mul(calc_compound_growth(self.variable_loan_interest_rate, delta_epoch, EPOCH_OF_YEAR)).unwrap()
             )
         }
 
@@ -281,6 +595,45 @@ mod lend_pool {
             self.calc_interest_rate(supply, variable_borrow, stable_borrow)
         }
 
+        fn add_stable_loan(&mut self, amount: Decimal, stable_rate: Decimal) -> Bucket{
+            let new_amount = self.stable_loan_amount.checked_add(amount).unwrap();
+            self.stable_loan_interest_rate = self.stable_loan_amount.checked_mul(self.stable_loan_interest_rate).unwrap()
+                .checked_add(amount.checked_mul(stable_rate).unwrap()).unwrap()
+                .checked_div(new_amount).unwrap();
+            self.stable_loan_amount = new_amount;
+
+            self.stable_loan_res_mgr.mint_ruid_non_fungible(StableLoanData{
+                loan_amount: amount,
+                rate: stable_rate,
+                last_epoch_at: Runtime::current_epoch().number()
+            })
+        }
+
+        fn remove_stable_loan(&mut self, amount: Decimal, rate: Decimal){
+            // The accrued interest is already in stable_loan_amount, so the whole amount is taken off.
+            // The final repayment may be greater than the total amount borrowed.
+            // This is because each loan repayment is calculated separately.
+            let previous_debt = self.stable_loan_amount.checked_mul(self.stable_loan_interest_rate).unwrap();
+            if amount >= self.stable_loan_amount{
+                self.stable_loan_amount = Decimal::ZERO;
+                self.stable_loan_interest_rate = Decimal::ZERO;
+            }
+            else{
+                self.stable_loan_amount = self.stable_loan_amount.checked_sub(amount).unwrap();
+                // The total grows at the average rate while each loan grows at its own, so a loan above
+                // the average can take off more than its share of the weighted rate.
+                let remain_debt = previous_debt.checked_sub(amount.checked_mul(rate).unwrap()).unwrap();
+                self.stable_loan_interest_rate = if remain_debt > Decimal::ZERO {
+                    remain_debt.checked_div(self.stable_loan_amount).unwrap()
+                } else { Decimal::ZERO };
+            }
+        }
+
+        fn get_borrow_ratio(&self) -> Decimal{
+            let supply = self.get_underlying_value();
+            if supply == Decimal::ZERO { Decimal::ZERO } else { self.get_loan_value().checked_div(supply).unwrap() }
+        }
+
         fn calc_interest_rate(&self, supply: Decimal, variable_borrow: Decimal, stable_borrow: Decimal) -> (Decimal, Decimal, Decimal){
 
             debug!("calc_interest_rate.0, var:{}, stable:{}, supply:{}", variable_borrow, stable_borrow, supply);
@@ -314,17 +667,19 @@ mod lend_pool {
     
                 // interest = equity value * (current index value - starting index value)
                 let recent_variable_interest = variable_borrow * (current_borrow_index - self.loan_index);
-                let delta_epoch_year = Decimal::from(delta_epoch) / Decimal::from(EPOCH_OF_YEAR);
-                let recent_stable_interest = self.stable_loan_amount * self.stable_loan_interest_rate * delta_epoch_year;
-                let recent_supply_interest = normalized_supply * (current_supply_index - self.deposit_interest_rate);
+                let current_stable_loan = self.get_stable_loan_value();
+                let recent_stable_interest = current_stable_loan - self.stable_loan_amount;
+                let recent_supply_interest = normalized_supply * (current_supply_index - self.deposit_index);
     
                 // the interest rate spread goes into the insurance pool
                 self.insurance_balance += recent_variable_interest + recent_stable_interest - recent_supply_interest;
     
-                debug!("update_index({}), borrow_index:{}, current:{}, supply_index:{}, current:{}, stable:{}, stable_avg_rate:{}", self.token.to_hex(), self.borrow_index, current_borrow_index, self.supply_index, current_supply_index, self.stable_borrow_amount, self.stable_avg_rate);
+                debug!("update_index({:?}), loan_index:{}, current:{}, deposit_index:{}, current:{}, stable:{}, stable_avg_rate:{}", self.underlying_token, self.loan_index, current_borrow_index, self.deposit_index, current_supply_index, current_stable_loan, self.stable_loan_interest_rate);
                 self.deposit_index = current_supply_index;
                 self.loan_index = current_borrow_index;
                 self.last_update = current_epoch;
+                self.stable_loan_amount = current_stable_loan;
+                self.stable_loan_last_update = current_epoch;
     
             }
         }
@@ -336,19 +691,55 @@ mod lend_pool {
             let variable_borrow = self.get_variable_share_quantity().checked_mul(variable_borrow_index).unwrap();
             let stable_borrow = self.get_stable_loan_value();
 
-            let (deposite_rate, variable_rate, _) = self.calc_interest_rate(supply, variable_borrow, stable_borrow);
+            // stateful models settle on the report, the views only read the rates.
+            let total_debt = variable_borrow.checked_add(stable_borrow).unwrap();
+            let borrow_ratio = if supply == Decimal::ZERO { Decimal::ZERO } else { total_debt.checked_div(supply).unwrap() };
+            self.report_borrow_ratio_to_component(borrow_ratio);
+
+            let (variable_rate, stable_rate, deposite_rate) = self.calc_interest_rate(supply, variable_borrow, stable_borrow);
             self.deposit_interest_rate = deposite_rate;
             self.variable_loan_interest_rate = variable_rate;
+
+            Runtime::emit_event(RateUpdateEvent{
+                deposit_rate: deposite_rate,
+                variable_rate,
+                stable_rate,
+                deposit_index: supply_index,
+                loan_index: variable_borrow_index
+            });
         }
 
         fn get_stable_loan_value(&self) -> Decimal{
-            let delta_epoch = Runtime::current_epoch().number() - self.last_update;
+            let delta_epoch = Runtime::current_epoch().number() - self.stable_loan_last_update;
             if delta_epoch == 0u64 {
                 return self.stable_loan_amount;
             }
-            self.stable_loan_amount.checked_mul(Decimal::ONE
-                .checked_add(Decimal::from(delta_epoch).checked_mul(self.stable_loan_interest_rate).unwrap().checked_div(Decimal::from(EPOCH_OF_YEAR)).unwrap()).unwrap()
-            ).unwrap()
+            self.stable_loan_amount.checked_mul(calc_compound_growth(self.stable_loan_interest_rate, delta_epoch, EPOCH_OF_YEAR)).unwrap()
+        }
+
+        /// The value of all stable loans and their weighted rate.
+        pub fn get_stable_loan_total(&self) -> (Decimal, Decimal){
+            (self.get_stable_loan_value(), self.stable_loan_interest_rate)
+        }
+
+        pub fn get_stable_loan(&self, loan_id: NonFungibleLocalId) -> StableLoanData{
+            self.stable_loan_res_mgr.get_non_fungible_data::<StableLoanData>(&loan_id)
+        }
+
+        pub fn get_stable_loan_token(&self) -> ResourceAddress{
+            self.stable_loan_res_mgr.address()
+        }
+
+        pub fn get_stable_loan_debt(&self, loan_id: NonFungibleLocalId) -> Decimal{
+            self.calc_stable_loan_debt(&self.get_stable_loan(loan_id))
+        }
+
+        fn calc_stable_loan_debt(&self, loan: &StableLoanData) -> Decimal{
+            let delta_epoch = Runtime::current_epoch().number() - loan.last_epoch_at;
+            let interest = ceil(loan.loan_amount
+                .checked_mul(calc_compound_growth(loan.rate, delta_epoch, EPOCH_OF_YEAR).checked_sub(Decimal::ONE).unwrap()).unwrap()
+            );
+            loan.loan_amount.checked_add(interest).unwrap()
         }
 
         pub fn get_redemption_value(&self, amount_of_pool_units: Decimal) -> Decimal{
@@ -369,7 +760,16 @@ mod lend_pool {
         }
 
         pub fn get_loan_value(&self) -> Decimal{
-            Decimal::ZERO
+            let (_, variable_borrow_index) = self.get_current_index();
+            self.variable_loan_share_quantity.checked_mul(variable_borrow_index).unwrap()
+                .checked_add(self.get_stable_loan_value()).unwrap()
+        }
+
+        fn assert_borrow_cap(&self, borrow_amount: Decimal){
+            assert!(
+                self.get_loan_value().checked_add(borrow_amount).unwrap() <= self.borrow_cap,
+                "the borrow cap of the pool is exceeded!"
+            );
         }
 
         pub fn get_variable_share_quantity(&self) -> Decimal{
@@ -380,6 +780,13 @@ mod lend_pool {
             self.interest_model_cmp.call_raw::<Decimal>("get_variable_interest_rate", scrypto_args!(borrow_ratio, self.interest_model))
         }
 
+        fn report_borrow_ratio_to_component(&self, borrow_ratio: Decimal){
+            // only the adaptive models keep a state to settle.
+            if let InterestModel::Adaptive(_) = self.interest_model {
+                self.interest_model_cmp.call_raw::<()>("report_borrow_ratio", scrypto_args!(borrow_ratio, self.interest_model))
+            }
+        }
+
         fn get_stable_rate_from_component(&self, borrow_ratio: Decimal, stable_ratio: Decimal) -> Decimal{
             self.interest_model_cmp.call_raw::<Decimal>("get_stable_interest_rate", scrypto_args!(borrow_ratio, stable_ratio, self.interest_model))
         }
@@ -1,35 +1,156 @@
 use scrypto::prelude::*;
 use crate::utils::*;
-use crate::keeper::{StakeData, UnstakeData};
+use crate::keeper::{StakeData, UnstakeData, ValidatorPerformance};
+use crate::events::{ContributeEvent, StakeEvent, RestakeEvent, RedeemEvent};
+
+/// A claim NFT of XRD unstaked by the pool itself, kept until the claim epoch.
+#[derive(ScryptoSbor, Eq, PartialEq, Debug, Clone)]
+pub struct PendingClaim {
+    pub validator: ComponentAddress,
+    pub claim_nft_id: NonFungibleLocalId,
+    pub claim_epoch: u64,
+    pub claim_amount: Decimal
+}
+
+/// A pool-wide ticket for XRD being unstaked, the pool keeps the validator's claim NFT
+/// and pays the XRD out for the ticket once the claim epoch is reached.
+#[derive(ScryptoSbor, NonFungibleData)]
+pub struct WithdrawalTicket {
+    pub validator: ComponentAddress,
+    pub claim_nft_id: NonFungibleLocalId,
+    pub claim_amount: Decimal,
+    pub claim_epoch: u64
+}
+
+/// How `contribute_auto` splits the stake across the validators tracked by the keeper.
+#[derive(ScryptoSbor, Eq, PartialEq, Debug, Clone, Copy)]
+pub enum AllocationStrategy {
+    EqualWeight,
+    /// in proportion to the APY of the current week, equal weight when no APY is known.
+    ApyWeighted,
+    /// equal weight over the validators holding no more than this share of the tracked stake.
+    StakeShareCapped(Decimal)
+}
+
+/// Splits `total` over the validators given as (validator, apy, staked), the last one taking the rounding remainder.
+pub fn allocate<K: Clone>(strategy: AllocationStrategy, total: Decimal, validators: &[(K, Decimal, Decimal)]) -> Vec<(K, Decimal)>{
+    assert!(!validators.is_empty(), "there is no validator to allocate to!");
+    let equal: Vec<(K, Decimal)> = validators.iter().map(|(k, _, _)| (k.clone(), Decimal::ONE)).collect();
+    let weights = match strategy {
+        AllocationStrategy::EqualWeight => equal,
+        AllocationStrategy::ApyWeighted => {
+            let weights: Vec<(K, Decimal)> = validators.iter()
+                .filter(|(_, apy, _)| *apy > Decimal::ZERO)
+                .map(|(k, apy, _)| (k.clone(), *apy))
+                .collect();
+            if weights.is_empty() { equal } else { weights }
+        },
+        AllocationStrategy::StakeShareCapped(max_share) => {
+            let total_staked = validators.iter().fold(Decimal::ZERO, |sum, (_, _, staked)| sum.checked_add(*staked).unwrap());
+            let weights: Vec<(K, Decimal)> = validators.iter()
+                .filter(|(_, _, staked)| total_staked == Decimal::ZERO || staked.checked_div(total_staked).unwrap() <= max_share)
+                .map(|(k, _, _)| (k.clone(), Decimal::ONE))
+                .collect();
+            if weights.is_empty() { equal } else { weights }
+        }
+    };
+
+    let weight_sum = weights.iter().fold(Decimal::ZERO, |sum, (_, weight)| sum.checked_add(*weight).unwrap());
+    let mut remain = total;
+    let last = weights.len() - 1;
+    weights.into_iter().enumerate().map(|(i, (k, weight))| {
+        let amount = if i == last { remain } else { floor(total.checked_mul(weight).unwrap().checked_div(weight_sum).unwrap()) };
+        remain = remain.checked_sub(amount).unwrap();
+        (k, amount)
+    }).collect()
+}
 
 #[blueprint]
+#[events(ContributeEvent, StakeEvent, RestakeEvent, RedeemEvent)]
 mod staking_pool {
 
-    // enable_method_auth!{
-    //     roles{
-    //         pool_owner => updatable_by: [];
-    //     },
-    //     methods {
-    //     }
-    // }
+    enable_method_auth!{
+        roles{
+            pool_owner => updatable_by: [];
+        },
+        methods {
+            //admin
+            set_allocation_strategy => restrict_to: [pool_owner, OWNER];
+            set_rebalance_params => restrict_to: [pool_owner, OWNER];
+            set_validator_rules => restrict_to: [pool_owner, OWNER];
+            set_buffer_params => restrict_to: [pool_owner, OWNER];
+            rebalance => restrict_to: [pool_owner, OWNER];
+
+            //readonly
+            get_allocation_strategy => PUBLIC;
+            get_rebalance_params => PUBLIC;
+            get_validator_rules => PUBLIC;
+            get_pending_claims => PUBLIC;
+            get_ticket_token => PUBLIC;
+            get_buffer => PUBLIC;
+            get_instant_fee_ratio => PUBLIC;
+            get_redemption_value => PUBLIC;
+            get_value_per_share => PUBLIC;
+            get_vault_amount => PUBLIC;
+
+            //business method
+            contribute => PUBLIC;
+            contribute_auto => PUBLIC;
+            redeem => PUBLIC;
+            redeem_ticket => PUBLIC;
+            claim_ticket => PUBLIC;
+            redeem_instant => PUBLIC;
+        }
+    }
 
     struct StakingResourePool{
         stake_token: ResourceAddress,
         staking_share_res_mgr: ResourceManager,
         validator_map: HashMap<ComponentAddress, StakeData>,
-        lsu_map: HashMap<ComponentAddress, Vault>
+        lsu_map: HashMap<ComponentAddress, Vault>,
+        validator_keeper: Global<AnyComponent>,
+        allocation_strategy: AllocationStrategy,
+        // validator => claim NFTs of the XRD unstaked by rebalancing, and of the withdrawal tickets.
+        claim_nft_map: HashMap<ComponentAddress, Vault>,
+        ticket_res_mgr: ResourceManager,
+        pending_claims: Vec<PendingClaim>,
+        pending_claim_amount: Decimal,
+        // claimed XRD waiting to be restaked.
+        pending_xrd: Vault,
+        // validators below this APY get no stake.
+        rebalance_min_apy: Decimal,
+        // validators charging a higher fee, or making a lower share of their proposals, as reported to the keeper, get no stake.
+        rebalance_max_fee: Decimal,
+        rebalance_min_uptime: Decimal,
+        // stake is only moved away from a validator above its target by more than this ratio.
+        rebalance_tolerance: Decimal,
+        // unstaked XRD paying out instant redemptions, kept at buffer_target_ratio of the pool value.
+        buffer: Vault,
+        buffer_target_ratio: Decimal,
+        // the instant redemption fee rises from min to max as the buffer depletes.
+        instant_min_fee: Decimal,
+        instant_max_fee: Decimal,
+        // shares of the value left once every share is redeemed, e.g. instant fees, kept by the pool
+        // so the next contributor does not take that value.
+        orphan_shares: Vault
     }
 
     impl StakingResourePool {
         
         pub fn instantiate(
             stake_token: ResourceAddress,
+            keeper_cmp_addr: ComponentAddress,
             owner_role: OwnerRole,
             pool_mgr_rule: AccessRule,
             address_reservation: Option<GlobalAddressReservation>
         ) -> (Global<StakingResourePool>, ResourceAddress) {
-            let (address_reservation, address) =
-                Runtime::allocate_component_address(StakingResourePool::blueprint_id());
+            let (address_reservation, address) = match address_reservation {
+                Some(address_reservation) => {
+                    let address = ComponentAddress::try_from(Runtime::get_reservation_address(&address_reservation)).unwrap();
+                    (address_reservation, address)
+                },
+                None => Runtime::allocate_component_address(StakingResourePool::blueprint_id())
+            };
 
             let staking_share_res_mgr: ResourceManager = ResourceBuilder::new_fungible(owner_role.clone())
                 .metadata(metadata!(init{
@@ -41,11 +162,26 @@ mod staking_pool {
 
                 }))
                 .mint_roles(mint_roles! {
-                    minter => pool_mgr_rule.clone();
+                    minter => rule!(require(global_caller(address)));
                     minter_updater => rule!(deny_all);
                 })
                 .burn_roles(burn_roles! {
-                    burner => pool_mgr_rule.clone();
+                    burner => rule!(require(global_caller(address)));
+                    burner_updater => rule!(deny_all);
+                })
+                .create_with_no_initial_supply();
+
+            let ticket_res_mgr = ResourceBuilder::new_ruid_non_fungible::<WithdrawalTicket>(OwnerRole::None)
+                .metadata(metadata!(init{
+                    "pool" => address, locked;
+                    "name" => "DeXian Staking Withdrawal Ticket", locked;
+                }))
+                .mint_roles(mint_roles! {
+                    minter => rule!(require(global_caller(address)));
+                    minter_updater => rule!(deny_all);
+                })
+                .burn_roles(burn_roles! {
+                    burner => rule!(require(global_caller(address)));
                     burner_updater => rule!(deny_all);
                 })
                 .create_with_no_initial_supply();
@@ -55,9 +191,30 @@ mod staking_pool {
                 validator_map: HashMap::new(),
                 lsu_map: HashMap::new(),
                 stake_token,
-                staking_share_res_mgr
+                staking_share_res_mgr,
+                validator_keeper: Global::from(keeper_cmp_addr),
+                allocation_strategy: AllocationStrategy::EqualWeight,
+                claim_nft_map: HashMap::new(),
+                ticket_res_mgr,
+                pending_claims: Vec::new(),
+                pending_claim_amount: Decimal::ZERO,
+                pending_xrd: Vault::new(stake_token),
+                rebalance_min_apy: Decimal::ZERO,
+                rebalance_max_fee: Decimal::ONE,
+                rebalance_min_uptime: Decimal::ZERO,
+                rebalance_tolerance: dec!("0.1"),
+                buffer: Vault::new(stake_token),
+                buffer_target_ratio: Decimal::ZERO,
+                instant_min_fee: dec!("0.001"),
+                instant_max_fee: dec!("0.05"),
+                orphan_shares: Vault::new(staking_share_token)
             }.instantiate()
             .prepare_to_globalize(owner_role)
+            .roles(
+                roles!(
+                    pool_owner => pool_mgr_rule;
+                )
+            )
             .with_address(address_reservation)
             .globalize();
             
@@ -66,11 +223,223 @@ mod staking_pool {
 
         pub fn contribute(&mut self, bucket: Bucket, validator_addr: ComponentAddress) -> Bucket{
             assert_resource(&bucket.resource_address(), &self.stake_token);
-            let (_, _, value_per_share) = self.get_values();
             let join_amount = bucket.amount();
+            let (share_bucket, share_amount) = self.mint_shares(join_amount);
+
+            let bucket = self.fund_buffer(bucket);
+            let stake_amount = bucket.amount();
+            if bucket.is_empty() {
+                bucket.drop_empty();
+            }
+            else{
+                let part_share = floor(share_amount.checked_mul(stake_amount).unwrap().checked_div(join_amount).unwrap());
+                self.stake_to(bucket, validator_addr, part_share);
+            }
+            self.emit_contribute(join_amount, stake_amount, share_amount);
+
+            share_bucket
+        }
+
+        /// Stakes across the validators tracked by the keeper, split by the allocation strategy.
+        pub fn contribute_auto(&mut self, bucket: Bucket) -> Bucket{
+            assert_resource(&bucket.resource_address(), &self.stake_token);
+            let join_amount = bucket.amount();
+            let (share_bucket, share_amount) = self.mint_shares(join_amount);
+
+            let mut bucket = self.fund_buffer(bucket);
+            let stake_amount = bucket.amount();
+            // nothing left to stake once the buffer is refilled, no validator is needed.
+            if stake_amount > Decimal::ZERO {
+                let validator_stats = self.validator_keeper.call_raw::<Vec<(ComponentAddress, Decimal, Decimal)>>("get_validator_stats", scrypto_args!());
+                for (validator_addr, amount) in allocate(self.allocation_strategy, stake_amount, &validator_stats) {
+                    if amount > Decimal::ZERO {
+                        let part_share = floor(share_amount.checked_mul(amount).unwrap().checked_div(join_amount).unwrap());
+                        self.stake_to(bucket.take(amount), validator_addr, part_share);
+                    }
+                }
+            }
+            bucket.drop_empty();
+            self.emit_contribute(join_amount, stake_amount, share_amount);
+
+            share_bucket
+        }
+
+        fn mint_shares(&mut self, join_amount: Decimal) -> (Bucket, Decimal){
+            let (total_value, staking_share_qty, value_per_share) = self.get_values();
+            assert!(value_per_share > Decimal::ZERO, "the pool has no value left to price the shares!");
+            if staking_share_qty == Decimal::ZERO && total_value > Decimal::ZERO {
+                let orphan_shares = self.staking_share_res_mgr.mint(total_value);
+                self.orphan_shares.put(orphan_shares);
+            }
             let share_amount = floor(join_amount.checked_div(value_per_share).unwrap());
-            let share_bucket = self.staking_share_res_mgr.mint(share_amount);
+            (self.staking_share_res_mgr.mint(share_amount), share_amount)
+        }
+
+        fn emit_contribute(&self, join_amount: Decimal, stake_amount: Decimal, share_amount: Decimal){
+            Runtime::emit_event(ContributeEvent{
+                amount: join_amount,
+                buffered: join_amount.checked_sub(stake_amount).unwrap(),
+                staked: stake_amount,
+                share_amount
+            });
+        }
+
+        pub fn set_allocation_strategy(&mut self, allocation_strategy: AllocationStrategy){
+            if let AllocationStrategy::StakeShareCapped(max_share) = allocation_strategy {
+                assert!(max_share > Decimal::ZERO && max_share <= Decimal::ONE, "the max stake share must be in (0, 1]!");
+            }
+            self.allocation_strategy = allocation_strategy;
+        }
+
+        pub fn get_allocation_strategy(&self) -> AllocationStrategy{
+            self.allocation_strategy
+        }
+
+        pub fn set_rebalance_params(&mut self, rebalance_min_apy: Decimal, rebalance_tolerance: Decimal){
+            assert!(rebalance_min_apy >= Decimal::ZERO, "the min APY must not be negative!");
+            assert!(rebalance_tolerance >= Decimal::ZERO, "the tolerance must not be negative!");
+            self.rebalance_min_apy = rebalance_min_apy;
+            self.rebalance_tolerance = rebalance_tolerance;
+        }
+
+        pub fn get_rebalance_params(&self) -> (Decimal, Decimal){
+            (self.rebalance_min_apy, self.rebalance_tolerance)
+        }
+
+        pub fn set_validator_rules(&mut self, rebalance_max_fee: Decimal, rebalance_min_uptime: Decimal){
+            assert!(rebalance_max_fee >= Decimal::ZERO && rebalance_max_fee <= Decimal::ONE, "the max fee must be between 0 and 1!");
+            assert!(rebalance_min_uptime >= Decimal::ZERO && rebalance_min_uptime <= Decimal::ONE, "the min uptime must be between 0 and 1!");
+            self.rebalance_max_fee = rebalance_max_fee;
+            self.rebalance_min_uptime = rebalance_min_uptime;
+        }
+
+        pub fn get_validator_rules(&self) -> (Decimal, Decimal){
+            (self.rebalance_max_fee, self.rebalance_min_uptime)
+        }
+
+        pub fn get_pending_claims(&self) -> Vec<PendingClaim>{
+            self.pending_claims.clone()
+        }
+
+        /// Claims the XRD of the matured claim NFTs, unstakes from the validators above their target
+        /// and restakes the claimed XRD to the validators below it. The targets split the pool value
+        /// less the buffer by the allocation strategy over the keeper's validators meeting the min APY,
+        /// the max fee and the min uptime.
+        pub fn rebalance(&mut self){
+            self.claim_matured();
+
+            let validator_stats: Vec<(ComponentAddress, Decimal, Decimal)> = self.validator_keeper
+                .call_raw::<Vec<(ComponentAddress, Decimal, Decimal)>>("get_validator_stats", scrypto_args!())
+                .into_iter()
+                .filter(|(validator_addr, apy, _)| *apy >= self.rebalance_min_apy && self.meets_validator_rules(validator_addr))
+                .collect();
+            assert!(!validator_stats.is_empty(), "there is no validator meeting the rebalance rules!");
+            let stake_amount = self.get_vault_amount().checked_sub(self.buffer.amount()).unwrap();
+            let targets: HashMap<ComponentAddress, Decimal> = allocate(self.allocation_strategy, stake_amount, &validator_stats)
+                .into_iter().collect();
+
+            let staked_validators: Vec<ComponentAddress> = self.lsu_map.keys().cloned().collect();
+            for validator_addr in staked_validators {
+                let target = targets.get(&validator_addr).copied().unwrap_or(Decimal::ZERO);
+                let staked = self.get_staked(&validator_addr);
+                let limit = target.checked_mul(Decimal::ONE.checked_add(self.rebalance_tolerance).unwrap()).unwrap();
+                if staked > limit {
+                    self.unstake_from(validator_addr, staked.checked_sub(target).unwrap());
+                }
+            }
+
+            for (validator_addr, target) in targets {
+                if self.pending_xrd.is_empty() {
+                    break;
+                }
+                let staked = self.get_staked(&validator_addr);
+                if staked < target {
+                    let deficit = target.checked_sub(staked).unwrap();
+                    let amount = if deficit < self.pending_xrd.amount() { deficit } else { self.pending_xrd.amount() };
+                    let bucket = self.pending_xrd.take(amount);
+                    self.delegate_stake(bucket, validator_addr);
+                    Runtime::emit_event(RestakeEvent{
+                        validator: validator_addr,
+                        amount
+                    });
+                }
+            }
+        }
 
+        fn meets_validator_rules(&self, validator_addr: &ComponentAddress) -> bool{
+            // a validator without a report counts as the worst, so it passes only the default rules.
+            let performance = self.validator_keeper
+                .call_raw::<Option<ValidatorPerformance>>("get_validator_performance", scrypto_args!(validator_addr.clone()))
+                .unwrap_or(ValidatorPerformance{ fee: Decimal::ONE, uptime: Decimal::ZERO });
+            performance.fee <= self.rebalance_max_fee && performance.uptime >= self.rebalance_min_uptime
+        }
+
+        fn claim_matured(&mut self){
+            let current_epoch = Runtime::current_epoch().number();
+            let (matured, pending): (Vec<PendingClaim>, Vec<PendingClaim>) = std::mem::take(&mut self.pending_claims)
+                .into_iter()
+                .partition(|claim| claim.claim_epoch <= current_epoch);
+            self.pending_claims = pending;
+            for claim in matured {
+                let claim_nft: Bucket = self.claim_nft_map.get_mut(&claim.validator).unwrap()
+                    .as_non_fungible().take_non_fungible(&claim.claim_nft_id).into();
+                let mut validator: Global<Validator> = Global::from(claim.validator);
+                self.pending_claim_amount = self.pending_claim_amount.checked_sub(claim.claim_amount).unwrap();
+                let xrd = self.fund_buffer(validator.claim_xrd(claim_nft));
+                self.pending_xrd.put(xrd);
+            }
+        }
+
+        fn unstake_from(&mut self, validator_addr: ComponentAddress, value: Decimal){
+            let lsu = self.lsu_map.get_mut(&validator_addr).unwrap();
+            let mut validator: Global<Validator> = Global::from(validator_addr);
+            let lsu_value = validator.get_redemption_value(lsu.amount());
+            let lsu_amount = if value >= lsu_value { lsu.amount() } else {
+                floor(value.checked_mul(lsu.amount()).unwrap().checked_div(lsu_value).unwrap())
+            };
+            if lsu_amount == Decimal::ZERO {
+                return;
+            }
+            let claim_nft = validator.unstake(lsu.take(lsu_amount));
+            let claim_nft_id = claim_nft.as_non_fungible().non_fungible_local_id();
+            let unstake_data = ResourceManager::from_address(claim_nft.resource_address()).get_non_fungible_data::<UnstakeData>(&claim_nft_id);
+
+            self.validator_map.entry(validator_addr).and_modify(|stake_data|{
+                stake_data.last_staked = lsu_value.checked_sub(unstake_data.claim_amount).unwrap();
+                stake_data.last_stake_epoch = Runtime::current_epoch().number();
+            });
+            self.pending_claims.push(PendingClaim{
+                validator: validator_addr,
+                claim_nft_id,
+                claim_epoch: unstake_data.claim_epoch.number(),
+                claim_amount: unstake_data.claim_amount
+            });
+            self.pending_claim_amount = self.pending_claim_amount.checked_add(unstake_data.claim_amount).unwrap();
+            self.claim_nft_map.entry(validator_addr).or_insert(Vault::new(claim_nft.resource_address())).put(claim_nft);
+        }
+
+        fn get_staked(&self, validator_addr: &ComponentAddress) -> Decimal{
+            match self.lsu_map.get(validator_addr) {
+                Some(lsu) if !lsu.is_empty() => {
+                    let validator: Global<Validator> = Global::from(validator_addr.clone());
+                    validator.get_redemption_value(lsu.amount())
+                },
+                _ => Decimal::ZERO
+            }
+        }
+
+        fn stake_to(&mut self, bucket: Bucket, validator_addr: ComponentAddress, share_amount: Decimal){
+            let join_amount = bucket.amount();
+            self.delegate_stake(bucket, validator_addr);
+
+            Runtime::emit_event(StakeEvent{
+                validator: validator_addr,
+                amount: join_amount,
+                share_amount
+            });
+        }
+
+        fn delegate_stake(&mut self, bucket: Bucket, validator_addr: ComponentAddress){
             let current_epoch = Runtime::current_epoch().number();
             let mut validator: Global<Validator> = Global::from(validator_addr);
             let lsu = validator.stake(bucket);
@@ -88,15 +457,121 @@ mod staking_pool {
                         last_lsu
                     }
             );
-
-            share_bucket
         }
 
+        /// Unstakes the value of the shares from the validator and returns the validator's claim NFT.
         pub fn redeem(&mut self, bucket: Bucket, validator_addr: ComponentAddress) -> Bucket{
+            self.unstake_shares(bucket, validator_addr)
+        }
+
+        /// Like `redeem`, but the pool keeps the claim NFT and returns a withdrawal ticket for it.
+        pub fn redeem_ticket(&mut self, bucket: Bucket, validator_addr: ComponentAddress) -> Bucket{
+            let claim_nft = self.unstake_shares(bucket, validator_addr);
+            let claim_nft_id = claim_nft.as_non_fungible().non_fungible_local_id();
+            let unstake_data = ResourceManager::from_address(claim_nft.resource_address()).get_non_fungible_data::<UnstakeData>(&claim_nft_id);
+            self.claim_nft_map.entry(validator_addr).or_insert(Vault::new(claim_nft.resource_address())).put(claim_nft);
+
+            self.ticket_res_mgr.mint_ruid_non_fungible(WithdrawalTicket{
+                validator: validator_addr,
+                claim_nft_id,
+                claim_amount: unstake_data.claim_amount,
+                claim_epoch: unstake_data.claim_epoch.number()
+            })
+        }
+
+        /// Pays the XRD of the ticket once its claim epoch is reached.
+        pub fn claim_ticket(&mut self, ticket: Bucket) -> Bucket{
+            assert_resource(&ticket.resource_address(), &self.ticket_res_mgr.address());
+            let ticket_data = ticket.as_non_fungible().non_fungible::<WithdrawalTicket>().data();
+            assert!(Runtime::current_epoch().number() >= ticket_data.claim_epoch, "the ticket can not be claimed yet!");
+
+            let claim_nft: Bucket = self.claim_nft_map.get_mut(&ticket_data.validator).unwrap()
+                .as_non_fungible().take_non_fungible(&ticket_data.claim_nft_id).into();
+            let mut validator: Global<Validator> = Global::from(ticket_data.validator);
+            ticket.burn();
+            validator.claim_xrd(claim_nft)
+        }
+
+        /// Pays the value of the shares from the buffer at once, less the instant fee.
+        /// The fee stays in the pool and raises the value per share.
+        pub fn redeem_instant(&mut self, bucket: Bucket) -> Bucket{
+            assert_resource(&bucket.resource_address(), &self.staking_share_res_mgr.address());
+            let redeem_value = self.get_redemption_value(bucket.amount());
+            let fee = ceil(redeem_value.checked_mul(self.get_instant_fee_ratio(redeem_value)).unwrap());
+            let pay_amount = redeem_value.checked_sub(fee).unwrap();
+            assert!(pay_amount <= self.buffer.amount(), "the buffer is insufficient for the instant redemption!");
+            let share_amount = bucket.amount();
+            self.staking_share_res_mgr.burn(bucket);
+
+            Runtime::emit_event(RedeemEvent{
+                validator: None,
+                share_amount,
+                redeem_value,
+                fee,
+                claim_nft_id: None
+            });
+
+            self.buffer.take(pay_amount)
+        }
+
+        /// min_fee + (max_fee - min_fee) * depletion, the depletion being how far the buffer
+        /// falls below its target after paying out the value.
+        pub fn get_instant_fee_ratio(&self, redeem_value: Decimal) -> Decimal{
+            let target = self.get_buffer_target();
+            let buffer = self.buffer.amount();
+            let remain = if buffer > redeem_value { buffer.checked_sub(redeem_value).unwrap() } else { Decimal::ZERO };
+            let depletion = if target == Decimal::ZERO || remain >= target { Decimal::ZERO } else {
+                Decimal::ONE.checked_sub(remain.checked_div(target).unwrap()).unwrap()
+            };
+            self.instant_min_fee.checked_add(
+                self.instant_max_fee.checked_sub(self.instant_min_fee).unwrap().checked_mul(depletion).unwrap()
+            ).unwrap()
+        }
+
+        pub fn set_buffer_params(&mut self, buffer_target_ratio: Decimal, instant_min_fee: Decimal, instant_max_fee: Decimal){
+            assert!(buffer_target_ratio >= Decimal::ZERO && buffer_target_ratio <= Decimal::ONE, "the buffer target ratio must be in [0, 1]!");
+            assert!(
+                instant_min_fee >= Decimal::ZERO && instant_min_fee <= instant_max_fee && instant_max_fee < Decimal::ONE,
+                "the fees must satisfy 0 <= min fee <= max fee < 1!"
+            );
+            self.buffer_target_ratio = buffer_target_ratio;
+            self.instant_min_fee = instant_min_fee;
+            self.instant_max_fee = instant_max_fee;
+        }
+
+        /// Returns the buffer balance and its target.
+        pub fn get_buffer(&self) -> (Decimal, Decimal){
+            (self.buffer.amount(), self.get_buffer_target())
+        }
+
+        fn get_buffer_target(&self) -> Decimal{
+            self.get_vault_amount().checked_mul(self.buffer_target_ratio).unwrap()
+        }
+
+        /// Keeps what the buffer is short of its target, returns the rest. The bucket is not yet
+        /// counted in the pool value, so the target includes it.
+        fn fund_buffer(&mut self, mut bucket: Bucket) -> Bucket{
+            let target = self.get_vault_amount().checked_add(bucket.amount()).unwrap()
+                .checked_mul(self.buffer_target_ratio).unwrap();
+            let buffer = self.buffer.amount();
+            if buffer < target {
+                let deficit = target.checked_sub(buffer).unwrap();
+                let amount = if deficit < bucket.amount() { deficit } else { bucket.amount() };
+                self.buffer.put(bucket.take(amount));
+            }
+            bucket
+        }
+
+        pub fn get_ticket_token(&self) -> ResourceAddress{
+            self.ticket_res_mgr.address()
+        }
+
+        fn unstake_shares(&mut self, bucket: Bucket, validator_addr: ComponentAddress) -> Bucket{
             assert_resource(&bucket.resource_address(), &self.staking_share_res_mgr.address());
             assert!(self.lsu_map.contains_key(&validator_addr), "the validator address not exists");
             let (_, _, value_per_share) = self.get_values();
-            let redeem_value = bucket.amount().checked_mul(value_per_share).unwrap();
+            let share_amount = bucket.amount();
+            let redeem_value = share_amount.checked_mul(value_per_share).unwrap();
             
             let lsu = self.lsu_map.get_mut(&validator_addr).unwrap();
             let mut validator: Global<Validator> = Global::from(validator_addr);
@@ -108,32 +583,50 @@ mod staking_pool {
             let claim_nft = validator.unstake(unstake_lsu_bucket);
             let claim_nft_id = claim_nft.as_non_fungible().non_fungible_local_id();
             let unstake_data = ResourceManager::from_address(claim_nft.resource_address()).get_non_fungible_data::<UnstakeData>(&claim_nft_id);
+            self.staking_share_res_mgr.burn(bucket);
 
             self.validator_map.entry(validator_addr).and_modify(|stake_data|{
                 stake_data.last_staked = lsu_value.checked_sub(unstake_data.claim_amount).unwrap();
                 stake_data.last_stake_epoch = Runtime::current_epoch().number();
             });
 
+            Runtime::emit_event(RedeemEvent{
+                validator: Some(validator_addr),
+                share_amount,
+                redeem_value,
+                fee: Decimal::ZERO,
+                claim_nft_id: Some(claim_nft_id)
+            });
+
             claim_nft
-            
         }
 
-        fn get_redemption_value(&self, amount_of_pool_units: Decimal) -> Decimal{
+        pub fn get_redemption_value(&self, amount_of_pool_units: Decimal) -> Decimal{
+            amount_of_pool_units.checked_mul(self.get_value_per_share()).unwrap()
+        }
+
+        pub fn get_value_per_share(&self) -> Decimal{
             let(_, _, value_per_share) = self.get_values();
-            amount_of_pool_units.checked_mul(value_per_share).unwrap()
+            value_per_share
         }
 
+        /// The staked XRD plus the XRD being unstaked or waiting to be restaked by rebalancing, and the buffer.
         pub fn get_vault_amount(&self) -> Decimal{
             self.sum_current_staked()
+                .checked_add(self.pending_claim_amount).unwrap()
+                .checked_add(self.pending_xrd.amount()).unwrap()
+                .checked_add(self.buffer.amount()).unwrap()
         }
 
         fn get_values(&self) -> (Decimal, Decimal, Decimal){
             let total_value = self.get_vault_amount();
             let staking_share_qty = self.staking_share_res_mgr.total_supply().unwrap();
+            // an empty pool values the first share at one stake token, any value left in it gets orphan shares at that price.
+            let value_per_share = if staking_share_qty == Decimal::ZERO { Decimal::ONE } else { total_value.checked_div(staking_share_qty).unwrap() };
             (
                 total_value,
                 staking_share_qty,
-                total_value.checked_div(staking_share_qty).unwrap()  //value_per_share
+                value_per_share
             )
         }
 
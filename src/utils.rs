@@ -10,6 +10,15 @@ pub fn floor(dec: Decimal) -> Decimal{
     dec.checked_round(18, RoundingMode::ToNegativeInfinity).unwrap()
 }
 
+/// Growth factor of a balance compounding every epoch: (1 + annual_rate / epoch_of_year) ** delta_epoch.
+pub fn calc_compound_growth(annual_rate: Decimal, delta_epoch: u64, epoch_of_year: u64) -> Decimal{
+    if delta_epoch == 0u64 {
+        return Decimal::ONE;
+    }
+    let epoch_rate = annual_rate.checked_div(Decimal::from(epoch_of_year)).unwrap();
+    Decimal::ONE.checked_add(epoch_rate).unwrap().checked_powi(delta_epoch as i64).unwrap()
+}
+
 pub fn assert_resource(res_addr: &ResourceAddress, expect_res_addr: &ResourceAddress){
     assert!(res_addr == expect_res_addr, "the resource address is not expect!");
 }
@@ -19,5 +28,5 @@ pub fn assert_vault_amount(vault: &Vault, not_less_than: Decimal){
 }
 
 pub fn assert_amount(v: Decimal, not_less_than: Decimal){
-    assert!(v < not_less_than, "target value less than expect!");
+    assert!(v >= not_less_than, "target value less than expect!");
 }
\ No newline at end of file
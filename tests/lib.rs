@@ -3,38 +3,659 @@ use scrypto::this_package;
 use scrypto_test::prelude::*;
 use scrypto_unit::*;
 
-use dse::test_bindings::*;
+use dse::utils::calc_compound_growth;
+use dse::events::*;
+
+const MAX_AGE_EPOCHS: u64 = 10;
+// keep in line with the lending pool.
+const EPOCH_OF_YEAR: u64 = 15017;
+// the keeper counts the weeks from this epoch, so the tests must run after it.
+const BABYLON_START_EPOCH: u64 = 32719;
+
+// a test runner with an allocated account and the package published.
+fn setup() -> (DefaultTestRunner, Secp256k1PublicKey, ComponentAddress, PackageAddress) {
+    let mut test_runner = TestRunnerBuilder::new().without_trace().build();
+    let (public_key, _private_key, account) = test_runner.new_allocated_account();
+    let package_address = test_runner.compile_and_publish(this_package!());
+    (test_runner, public_key, account, package_address)
+}
+
+fn instantiate_oracle(
+    test_runner: &mut DefaultTestRunner,
+    package_address: PackageAddress,
+    public_key: &Secp256k1PublicKey,
+    account: ComponentAddress
+) -> (ComponentAddress, ResourceAddress) {
+    let feeder_rule = rule!(require(NonFungibleGlobalId::from_public_key(public_key)));
+    let manifest = ManifestBuilder::new()
+        .call_function(
+            package_address,
+            "PriceOracle",
+            "instantiate",
+            manifest_args!(feeder_rule, MAX_AGE_EPOCHS, dec!("0.1")),
+        )
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    let receipt = test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(public_key)],
+    );
+    let commit = receipt.expect_commit(true);
+    (commit.new_component_addresses()[0], commit.new_resource_addresses()[0])
+}
+
+fn set_price(
+    test_runner: &mut DefaultTestRunner,
+    public_key: &Secp256k1PublicKey,
+    oracle: ComponentAddress,
+    res_addr: ResourceAddress,
+    price: Decimal
+) -> TransactionReceipt {
+    let manifest = ManifestBuilder::new()
+        .call_method(oracle, "set_price", manifest_args!(res_addr, price))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(public_key)],
+    )
+}
+
+fn get_price(
+    test_runner: &mut DefaultTestRunner,
+    oracle: ComponentAddress,
+    base: ResourceAddress,
+    quote: ResourceAddress
+) -> TransactionReceipt {
+    let manifest = ManifestBuilder::new()
+        .call_method(oracle, "get_price", manifest_args!(base, quote))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(manifest, vec![])
+}
+
+#[test]
+fn test_oracle_price_pushed_by_feeder() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (oracle, _) = instantiate_oracle(&mut test_runner, package_address, &public_key, account);
+    let token = test_runner.create_fungible_resource(dec!("1000"), 18, account);
+
+    set_price(&mut test_runner, &public_key, oracle, token, dec!("2")).expect_commit_success();
+    set_price(&mut test_runner, &public_key, oracle, XRD, dec!("0.5")).expect_commit_success();
+
+    let receipt = get_price(&mut test_runner, oracle, token, XRD);
+    assert_eq!(receipt.expect_commit_success().output::<Decimal>(0), dec!("4"));
+    let receipt = get_price(&mut test_runner, oracle, XRD, token);
+    assert_eq!(receipt.expect_commit_success().output::<Decimal>(0), dec!("0.25"));
+
+    // unsigned pushes are rejected.
+    let manifest = ManifestBuilder::new()
+        .call_method(oracle, "set_price", manifest_args!(token, dec!("2")))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(manifest, vec![]).expect_commit_failure();
+}
+
+#[test]
+fn test_oracle_rejects_out_of_band_price() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (oracle, _) = instantiate_oracle(&mut test_runner, package_address, &public_key, account);
+
+    set_price(&mut test_runner, &public_key, oracle, XRD, dec!("1")).expect_commit_success();
+    set_price(&mut test_runner, &public_key, oracle, XRD, dec!("1.1")).expect_commit_success();
+    set_price(&mut test_runner, &public_key, oracle, XRD, dec!("1.5")).expect_commit_failure();
+
+    // once the last price is stale it no longer bounds the next one.
+    let current_epoch = test_runner.get_current_epoch();
+    test_runner.set_current_epoch(current_epoch.after(MAX_AGE_EPOCHS + 1).unwrap());
+    set_price(&mut test_runner, &public_key, oracle, XRD, dec!("1.5")).expect_commit_success();
+}
+
+#[test]
+fn test_oracle_band_moves_once_per_epoch() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (oracle, _) = instantiate_oracle(&mut test_runner, package_address, &public_key, account);
+
+    // every push of the epoch is bounded by the same reference of 1.
+    set_price(&mut test_runner, &public_key, oracle, XRD, dec!("1")).expect_commit_success();
+    set_price(&mut test_runner, &public_key, oracle, XRD, dec!("1.1")).expect_commit_success();
+    set_price(&mut test_runner, &public_key, oracle, XRD, dec!("1.05")).expect_commit_success();
+    set_price(&mut test_runner, &public_key, oracle, XRD, dec!("1.2")).expect_commit_failure();
+
+    // the next epoch takes the last price of 1.05 as the reference.
+    let current_epoch = test_runner.get_current_epoch();
+    test_runner.set_current_epoch(current_epoch.after(1).unwrap());
+    set_price(&mut test_runner, &public_key, oracle, XRD, dec!("1.2")).expect_commit_failure();
+    set_price(&mut test_runner, &public_key, oracle, XRD, dec!("1.155")).expect_commit_success();
+    set_price(&mut test_runner, &public_key, oracle, XRD, dec!("1.2")).expect_commit_failure();
+}
 
 #[test]
-fn test_hello() {
-    // Setup the environment
-    let mut test_runner = TestRunnerBuilder::new().build();
+fn test_oracle_rejects_stale_price() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (oracle, _) = instantiate_oracle(&mut test_runner, package_address, &public_key, account);
+    let token = test_runner.create_fungible_resource(dec!("1000"), 18, account);
+
+    set_price(&mut test_runner, &public_key, oracle, token, dec!("2")).expect_commit_success();
+    set_price(&mut test_runner, &public_key, oracle, XRD, dec!("1")).expect_commit_success();
 
-    // Create an account
+    let current_epoch = test_runner.get_current_epoch();
+    test_runner.set_current_epoch(current_epoch.after(MAX_AGE_EPOCHS).unwrap());
+    get_price(&mut test_runner, oracle, token, XRD).expect_commit_success();
+
+    test_runner.set_current_epoch(current_epoch.after(MAX_AGE_EPOCHS + 1).unwrap());
+    get_price(&mut test_runner, oracle, token, XRD).expect_commit_failure();
+}
+
+// builds the package with the `test` feature, which adds the mock blueprints.
+fn compile_and_publish_with_test_feature(test_runner: &mut DefaultTestRunner) -> PackageAddress {
+    let (code, definition) = Compile::compile_with_env_vars(
+        this_package!(),
+        btreemap!(
+            "RUSTFLAGS".to_owned() => "--cfg feature=\"test\"".to_owned(),
+            "CARGO_ENCODED_RUSTFLAGS".to_owned() => "".to_owned()
+        )
+    );
+    test_runner.publish_package(code, definition, BTreeMap::new(), OwnerRole::None)
+}
+
+#[test]
+fn test_oracle_reads_mock_feed() {
+    let mut test_runner = TestRunnerBuilder::new().without_trace().build();
     let (public_key, _private_key, account) = test_runner.new_allocated_account();
+    let package_address = compile_and_publish_with_test_feature(&mut test_runner);
+    let (oracle, admin_badge) = instantiate_oracle(&mut test_runner, package_address, &public_key, account);
+    let token = test_runner.create_fungible_resource(dec!("1000"), 18, account);
 
-    // Publish package
-    let package_address = test_runner.compile_and_publish(this_package!());
+    let manifest = ManifestBuilder::new()
+        .call_function(package_address, "MockPriceFeed", "instantiate", manifest_args!())
+        .build();
+    let receipt = test_runner.execute_manifest_ignoring_fee(manifest, vec![]);
+    let feed = receipt.expect_commit(true).new_component_addresses()[0];
+
+    let manifest = ManifestBuilder::new()
+        .create_proof_from_account_of_amount(account, admin_badge, dec!("1"))
+        .call_method(oracle, "set_price_feed", manifest_args!(token, feed))
+        .call_method(feed, "set_price", manifest_args!(token, dec!("3")))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(&public_key)],
+    ).expect_commit_success();
+    set_price(&mut test_runner, &public_key, oracle, XRD, dec!("1")).expect_commit_success();
 
-    // Test the `instantiate_hello` function.
+    let receipt = get_price(&mut test_runner, oracle, token, XRD);
+    assert_eq!(receipt.expect_commit_success().output::<Decimal>(0), dec!("3"));
+
+    // the feed prices are bounded by the reference of 3 as well.
+    let set_feed_price = |test_runner: &mut DefaultTestRunner, price: Decimal| {
+        let manifest = ManifestBuilder::new()
+            .call_method(feed, "set_price", manifest_args!(token, price))
+            .build();
+        test_runner.execute_manifest_ignoring_fee(manifest, vec![]).expect_commit_success();
+    };
+    set_feed_price(&mut test_runner, dec!("3.3"));
+    get_price(&mut test_runner, oracle, token, XRD).expect_commit_success();
+    set_feed_price(&mut test_runner, dec!("3.6"));
+    get_price(&mut test_runner, oracle, token, XRD).expect_commit_failure();
+
+    // the feed reports a price older than the max age.
+    let current_epoch = test_runner.get_current_epoch();
+    test_runner.set_current_epoch(current_epoch.after(MAX_AGE_EPOCHS + 1).unwrap());
+    set_price(&mut test_runner, &public_key, oracle, XRD, dec!("1")).expect_commit_success();
+    let manifest = ManifestBuilder::new()
+        .call_method(feed, "set_price_at", manifest_args!(token, dec!("3"), current_epoch.number()))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(manifest, vec![]).expect_commit_success();
+    get_price(&mut test_runner, oracle, token, XRD).expect_commit_failure();
+
+    // nor can it report a price from a future epoch.
+    let future_epoch = test_runner.get_current_epoch().after(1).unwrap();
+    let manifest = ManifestBuilder::new()
+        .call_method(feed, "set_price_at", manifest_args!(token, dec!("3"), future_epoch.number()))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(manifest, vec![]).expect_commit_success();
+    get_price(&mut test_runner, oracle, token, XRD).expect_commit_failure();
+}
+
+fn assert_close(a: Decimal, b: Decimal, tolerance: Decimal) {
+    let delta = if a > b { a - b } else { b - a };
+    assert!(delta <= tolerance, "{} and {} differ by more than {}", a, b, tolerance);
+}
+
+#[test]
+fn test_compound_growth_over_a_year() {
+    assert_eq!(calc_compound_growth(dec!("0.1"), 0, EPOCH_OF_YEAR), Decimal::ONE);
+    assert_eq!(calc_compound_growth(Decimal::ZERO, EPOCH_OF_YEAR, EPOCH_OF_YEAR), Decimal::ONE);
+
+    // compounding every epoch for a year approaches e^0.1 = 1.10517...
+    let growth = calc_compound_growth(dec!("0.1"), EPOCH_OF_YEAR, EPOCH_OF_YEAR);
+    assert!(growth > dec!("1.1051") && growth < dec!("1.10518"));
+    // which is more than the simple interest the rate quotes.
+    assert!(growth > dec!("1.1"));
+}
+
+fn instantiate_keeper(
+    test_runner: &mut DefaultTestRunner,
+    package_address: PackageAddress,
+    public_key: &Secp256k1PublicKey,
+    account: ComponentAddress
+) -> (ComponentAddress, ResourceAddress) {
+    test_runner.set_current_epoch(Epoch::of(BABYLON_START_EPOCH + 1));
+    let manifest = ManifestBuilder::new()
+        .call_function(package_address, "ValidatorKeeper", "instantiate", manifest_args!())
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    let receipt = test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(public_key)],
+    );
+    let commit = receipt.expect_commit(true);
+    (commit.new_component_addresses()[0], commit.new_resource_addresses()[0])
+}
+
+// mirrors `dse::interest::InterestModel` for the manifests.
+#[derive(ManifestSbor)]
+enum InterestModel {
+    Default,
+    #[allow(dead_code)]
+    StableCoin,
+    Kinked,
+    Named(String),
+    Adaptive(String)
+}
+
+#[derive(ManifestSbor)]
+#[allow(dead_code)]
+enum CurveCoefficient {
+    Primary,
+    Quadratic,
+    BaseRate,
+    OptimalUtilization,
+    Slope1,
+    Slope2
+}
+
+#[derive(ManifestSbor)]
+#[allow(dead_code)]
+enum InterestCurve {
+    Quadratic{ primary: Decimal, quadratic: Decimal },
+    StableCoin{ primary: Decimal, quadratic: Decimal },
+    Kinked(KinkedParams)
+}
+
+#[derive(ManifestSbor)]
+struct KinkedParams {
+    base_rate: Decimal,
+    optimal_utilization: Decimal,
+    slope1: Decimal,
+    slope2: Decimal
+}
+
+fn default_kinked_params() -> KinkedParams {
+    KinkedParams {
+        base_rate: dec!("0.02"),
+        optimal_utilization: dec!("0.8"),
+        slope1: dec!("0.04"),
+        slope2: dec!("0.6")
+    }
+}
+
+fn instantiate_interest_model(
+    test_runner: &mut DefaultTestRunner,
+    package_address: PackageAddress,
+    public_key: &Secp256k1PublicKey,
+    account: ComponentAddress,
+    keeper: ComponentAddress,
+    kinked: KinkedParams
+) -> TransactionReceipt {
+    let manifest = ManifestBuilder::new()
+        .call_function(
+            package_address,
+            "DefInterestModel",
+            "new",
+            manifest_args!(keeper, dec!("0.2"), dec!("0.5"), dec!("0.55"), dec!("0.45"), kinked),
+        )
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(public_key)],
+    )
+}
+
+// a keeper and a default model on the kinked curve, with the model's admin badge.
+fn instantiate_kinked_interest_model(
+    test_runner: &mut DefaultTestRunner,
+    package_address: PackageAddress,
+    public_key: &Secp256k1PublicKey,
+    account: ComponentAddress,
+    kinked: KinkedParams
+) -> (ComponentAddress, ResourceAddress) {
+    let (keeper, _) = instantiate_keeper(test_runner, package_address, public_key, account);
+    let receipt = instantiate_interest_model(test_runner, package_address, public_key, account, keeper, kinked);
+    let commit = receipt.expect_commit(true);
+    (commit.new_component_addresses()[0], commit.new_resource_addresses()[0])
+}
+
+fn instantiate_lend_pool(
+    test_runner: &mut DefaultTestRunner,
+    package_address: PackageAddress,
+    public_key: &Secp256k1PublicKey,
+    account: ComponentAddress
+) -> (ComponentAddress, ResourceAddress, ResourceAddress) {
+    let (interest_model, _) = instantiate_kinked_interest_model(test_runner, package_address, public_key, account, default_kinked_params());
+    instantiate_lend_pool_with_model(test_runner, package_address, account, interest_model, InterestModel::Default)
+}
+
+// an XRD pool on the given model, managed by a new badge held by the account.
+fn instantiate_lend_pool_with_model(
+    test_runner: &mut DefaultTestRunner,
+    package_address: PackageAddress,
+    account: ComponentAddress,
+    interest_model: ComponentAddress,
+    model: InterestModel
+) -> (ComponentAddress, ResourceAddress, ResourceAddress) {
+    let pool_badge = test_runner.create_fungible_resource(dec!("1"), 0, account);
+    let pool_mgr_rule = rule!(require(pool_badge));
     let manifest = ManifestBuilder::new()
         .call_function(
             package_address,
-            "Hello",
-            "instantiate_hello",
-            manifest_args!(),
+            "LendResourcePool",
+            "instantiate",
+            manifest_args!(
+                XRD,
+                interest_model,
+                model,
+                dec!("0.1"),
+                OwnerRole::Fixed(pool_mgr_rule.clone()),
+                pool_mgr_rule,
+                None::<ManifestAddressReservation>
+            ),
+        )
+        .build();
+    let receipt = test_runner.execute_manifest_ignoring_fee(manifest, vec![]);
+    let (pool, deposit_share_token) = receipt.expect_commit_success().output::<(ComponentAddress, ResourceAddress)>(0);
+    (pool, deposit_share_token, pool_badge)
+}
+
+fn call_and_decode<T: ScryptoDecode>(
+    test_runner: &mut DefaultTestRunner,
+    component: ComponentAddress,
+    method: &str,
+    args: impl ResolvableArguments
+) -> T {
+    let manifest = ManifestBuilder::new()
+        .call_method(component, method, args)
+        .build();
+    test_runner.execute_manifest_ignoring_fee(manifest, vec![]).expect_commit_success().output::<T>(0)
+}
+
+// calls the method with a proof of the badge, anything returned goes to the account.
+fn call_with_badge(
+    test_runner: &mut DefaultTestRunner,
+    public_key: &Secp256k1PublicKey,
+    account: ComponentAddress,
+    badge: ResourceAddress,
+    component: ComponentAddress,
+    method: &str,
+    args: impl ResolvableArguments
+) -> TransactionReceipt {
+    let manifest = ManifestBuilder::new()
+        .create_proof_from_account_of_amount(account, badge, dec!("1"))
+        .call_method(component, method, args)
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(public_key)],
+    )
+}
+
+// the id of the non fungible of the resource the component received in the transaction.
+fn get_received_non_fungible(
+    receipt: &TransactionReceipt,
+    component: ComponentAddress,
+    res_addr: ResourceAddress
+) -> NonFungibleLocalId {
+    let balance_changes = &receipt.expect_commit_success().state_update_summary.balance_changes;
+    match balance_changes.get(&GlobalAddress::from(component)).and_then(|changes| changes.get(&res_addr)) {
+        Some(BalanceChange::NonFungible{ added, .. }) => added.iter().next().unwrap().clone(),
+        _ => panic!("the component received no non fungible of the resource")
+    }
+}
+
+fn deposit(
+    test_runner: &mut DefaultTestRunner,
+    public_key: &Secp256k1PublicKey,
+    account: ComponentAddress,
+    pool: ComponentAddress,
+    amount: Decimal
+) -> TransactionReceipt {
+    let manifest = ManifestBuilder::new()
+        .withdraw_from_account(account, XRD, amount)
+        .take_all_from_worktop(XRD, "deposit")
+        .call_method_with_name_lookup(pool, "add_liquity", |lookup| (lookup.bucket("deposit"),))
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(public_key)],
+    )
+}
+
+#[test]
+fn test_deposit_is_vaulted() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (pool, deposit_share_token, _) = instantiate_lend_pool(&mut test_runner, package_address, &public_key, account);
+
+    deposit(&mut test_runner, &public_key, account, pool, dec!("1000")).expect_commit_success();
+
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, pool, "get_available", manifest_args!()), dec!("1000"));
+    assert_eq!(test_runner.get_component_balance(account, deposit_share_token), dec!("1000"));
+}
+
+#[test]
+fn test_deposit_borrow_accrue_withdraw_conserves_funds() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (pool, deposit_share_token, pool_badge) = instantiate_lend_pool(&mut test_runner, package_address, &public_key, account);
+
+    deposit(&mut test_runner, &public_key, account, pool, dec!("1000")).expect_commit_success();
+
+    let manifest = ManifestBuilder::new()
+        .create_proof_from_account_of_amount(account, pool_badge, dec!("1"))
+        .call_method(pool, "borrow_variable", manifest_args!(dec!("500")))
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(&public_key)],
+    ).expect_commit_success();
+    let loan_share = call_and_decode::<Decimal>(&mut test_runner, pool, "get_variable_share_quantity", manifest_args!());
+    assert_eq!(loan_share, dec!("500"));
+
+    // accrue over several epochs, with interactions in between.
+    for _ in 0..5 {
+        let current_epoch = test_runner.get_current_epoch();
+        test_runner.set_current_epoch(current_epoch.after(300).unwrap());
+        call_and_decode::<()>(&mut test_runner, pool, "accrue_interest", manifest_args!());
+    }
+
+    let debt = call_and_decode::<Decimal>(&mut test_runner, pool, "get_loan_value", manifest_args!());
+    assert!(debt > dec!("500"));
+
+    // over-repay, the change goes back to the account.
+    let manifest = ManifestBuilder::new()
+        .create_proof_from_account_of_amount(account, pool_badge, dec!("1"))
+        .withdraw_from_account(account, XRD, dec!("600"))
+        .take_all_from_worktop(XRD, "repay")
+        .call_method_with_name_lookup(pool, "repay_variable", |lookup| (lookup.bucket("repay"), loan_share))
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(&public_key)],
+    ).expect_commit_success();
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, pool, "get_variable_share_quantity", manifest_args!()), Decimal::ZERO);
+    let available = call_and_decode::<Decimal>(&mut test_runner, pool, "get_available", manifest_args!());
+    assert_close(available, dec!("500") + debt, dec!("0.000000000001"));
+
+    let redemption = call_and_decode::<Decimal>(&mut test_runner, pool, "get_redemption_value", manifest_args!(dec!("1000")));
+    assert!(redemption > dec!("1000"));
+
+    let manifest = ManifestBuilder::new()
+        .withdraw_from_account(account, deposit_share_token, dec!("1000"))
+        .take_all_from_worktop(deposit_share_token, "shares")
+        .call_method_with_name_lookup(pool, "remove_liquity", |lookup| (lookup.bucket("shares"),))
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(&public_key)],
+    ).expect_commit_success();
+
+    // the interest paid is split between the depositors and the insurance, nothing else remains.
+    let (insurance, _) = call_and_decode::<(Decimal, Decimal)>(&mut test_runner, pool, "get_insurance", manifest_args!());
+    let remain = call_and_decode::<Decimal>(&mut test_runner, pool, "get_available", manifest_args!());
+    assert!(insurance > Decimal::ZERO);
+    assert_close(remain, insurance, dec!("0.000000000001"));
+    assert_close(debt - dec!("500"), redemption - dec!("1000") + insurance, dec!("0.000000000001"));
+}
+
+// the indexes of a pool with 1000 deposited and 500 borrowed after `epochs`, accruing every `interval` epochs.
+fn get_index_after_accruals(epochs: u64, interval: u64) -> (Decimal, Decimal) {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (pool, _, pool_badge) = instantiate_lend_pool(&mut test_runner, package_address, &public_key, account);
+    deposit(&mut test_runner, &public_key, account, pool, dec!("1000")).expect_commit_success();
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "borrow_variable", manifest_args!(dec!("500")))
+        .expect_commit_success();
+
+    let start_epoch = test_runner.get_current_epoch();
+    for elapsed in (interval..=epochs).step_by(interval as usize) {
+        test_runner.set_current_epoch(start_epoch.after(elapsed).unwrap());
+        call_and_decode::<()>(&mut test_runner, pool, "accrue_interest", manifest_args!());
+    }
+    assert_eq!(call_and_decode::<u64>(&mut test_runner, pool, "get_last_update", manifest_args!()), start_epoch.number() + epochs);
+    call_and_decode::<(Decimal, Decimal)>(&mut test_runner, pool, "get_current_index", manifest_args!())
+}
+
+#[test]
+fn test_pool_indexes_do_not_depend_on_accrual_frequency() {
+    let (deposit_index_once, loan_index_once) = get_index_after_accruals(60, 60);
+    let (deposit_index_often, loan_index_often) = get_index_after_accruals(60, 1);
+    assert!(loan_index_once > Decimal::ONE && deposit_index_once > Decimal::ONE);
+
+    // the rates are re-evaluated on every accrual as the utilization drifts, which
+    // moves the indexes by well under the tolerance.
+    assert_close(loan_index_once, loan_index_often, dec!("0.000001"));
+    assert_close(deposit_index_once, deposit_index_often, dec!("0.000001"));
+    // compounded, so above the linear accrual at the same rate.
+    assert!(loan_index_once > Decimal::ONE + dec!("0.225") * dec!("60") / Decimal::from(EPOCH_OF_YEAR));
+}
+
+fn get_events<T: ScryptoDecode>(
+    test_runner: &DefaultTestRunner,
+    receipt: &TransactionReceipt,
+    emitter: ComponentAddress,
+    name: &str
+) -> Vec<T> {
+    receipt.expect_commit_success().application_events.iter()
+        .filter(|(event_type_identifier, _)| match &event_type_identifier.0 {
+            Emitter::Method(node_id, ModuleId::Main) => node_id == emitter.as_node_id(),
+            _ => false
+        })
+        .filter(|(event_type_identifier, _)| test_runner.event_name(event_type_identifier) == name)
+        .map(|(_, data)| scrypto_decode::<T>(data).unwrap())
+        .collect()
+}
+
+#[test]
+fn test_lend_pool_emits_events() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (pool, deposit_share_token, pool_badge) = instantiate_lend_pool(&mut test_runner, package_address, &public_key, account);
+
+    let receipt = deposit(&mut test_runner, &public_key, account, pool, dec!("1000"));
+    assert_eq!(
+        get_events::<DepositEvent>(&test_runner, &receipt, pool, "DepositEvent"),
+        vec![DepositEvent{ amount: dec!("1000"), share_amount: dec!("1000") }]
+    );
+    assert_eq!(get_events::<RateUpdateEvent>(&test_runner, &receipt, pool, "RateUpdateEvent").len(), 1);
+
+    let manifest = ManifestBuilder::new()
+        .create_proof_from_account_of_amount(account, pool_badge, dec!("1"))
+        .call_method(pool, "borrow_variable", manifest_args!(dec!("500")))
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    let receipt = test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(&public_key)],
+    );
+    // 0.2 * 0.5 + 0.5 * 0.5 ** 2 at half utilization.
+    assert_eq!(
+        get_events::<BorrowEvent>(&test_runner, &receipt, pool, "BorrowEvent"),
+        vec![BorrowEvent{ amount: dec!("500"), stable: false, rate: dec!("0.225") }]
+    );
+    let rate_update = get_events::<RateUpdateEvent>(&test_runner, &receipt, pool, "RateUpdateEvent");
+    assert_eq!(rate_update[0].variable_rate, dec!("0.225"));
+    assert_eq!(rate_update[0].deposit_rate, dec!("0.225") * dec!("0.5") * dec!("0.9"));
+
+    let manifest = ManifestBuilder::new()
+        .create_proof_from_account_of_amount(account, pool_badge, dec!("1"))
+        .withdraw_from_account(account, XRD, dec!("600"))
+        .take_all_from_worktop(XRD, "repay")
+        .call_method_with_name_lookup(pool, "repay_variable", |lookup| (lookup.bucket("repay"), dec!("500")))
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
         )
         .build();
     let receipt = test_runner.execute_manifest_ignoring_fee(
         manifest,
         vec![NonFungibleGlobalId::from_public_key(&public_key)],
     );
-    println!("{:?}\n", receipt);
-    let component = receipt.expect_commit(true).new_component_addresses()[0];
+    assert_eq!(
+        get_events::<RepayEvent>(&test_runner, &receipt, pool, "RepayEvent"),
+        vec![RepayEvent{ amount: dec!("500"), stable: false }]
+    );
 
-    // Test the `free_token` method.
     let manifest = ManifestBuilder::new()
-        .call_method(component, "free_token", manifest_args!())
+        .withdraw_from_account(account, deposit_share_token, dec!("1000"))
+        .take_all_from_worktop(deposit_share_token, "shares")
+        .call_method_with_name_lookup(pool, "remove_liquity", |lookup| (lookup.bucket("shares"),))
         .call_method(
             account,
             "deposit_batch",
@@ -45,24 +666,2128 @@ fn test_hello() {
         manifest,
         vec![NonFungibleGlobalId::from_public_key(&public_key)],
     );
-    println!("{:?}\n", receipt);
-    receipt.expect_commit_success();
+    assert_eq!(
+        get_events::<WithdrawEvent>(&test_runner, &receipt, pool, "WithdrawEvent"),
+        vec![WithdrawEvent{ amount: dec!("1000"), share_amount: dec!("1000") }]
+    );
+}
+
+#[test]
+fn test_rebalance_stable_rate_on_each_trigger() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (pool, _, pool_badge) = instantiate_lend_pool(&mut test_runner, package_address, &public_key, account);
+    deposit(&mut test_runner, &public_key, account, pool, dec!("1000")).expect_commit_success();
+
+    // the first loan is taken at no utilization and 0%, the second at 0.2 * 0.05 + 0.5 * 0.05 ** 2.
+    let receipt = call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "borrow_stable", manifest_args!(dec!("50")));
+    let loan_token = call_and_decode::<ResourceAddress>(&mut test_runner, pool, "get_stable_loan_token", manifest_args!());
+    let first_loan = get_received_non_fungible(&receipt, account, loan_token);
+    let receipt = call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "borrow_stable", manifest_args!(dec!("50")));
+    let second_loan = get_received_non_fungible(&receipt, account, loan_token);
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "borrow_variable", manifest_args!(dec!("300")))
+        .expect_commit_success();
+
+    let stable_rate_of = |test_runner: &mut DefaultTestRunner, loan_id: &NonFungibleLocalId| call_and_decode::<dse::pools::lending::StableLoanData>(
+        test_runner, pool, "get_stable_loan", manifest_args!(loan_id.clone())
+    ).rate;
+    assert_eq!(stable_rate_of(&mut test_runner, &first_loan), Decimal::ZERO);
+    assert_eq!(stable_rate_of(&mut test_runner, &second_loan), dec!("0.01125"));
+    let rebalance = |test_runner: &mut DefaultTestRunner, loan_id: &NonFungibleLocalId| test_runner.execute_manifest_ignoring_fee(
+        ManifestBuilder::new().call_method(pool, "rebalance_stable_rate", manifest_args!(loan_id.clone())).build(),
+        vec![]
+    );
+
+    // at 0.4 utilization the stable rate is 0.16, while the deposit rate is well below 0.4 of it.
+    rebalance(&mut test_runner, &first_loan).expect_commit_failure();
+
+    // the utilization trigger.
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "set_rebalance_params", manifest_args!(dec!("0.4"), Decimal::ONE))
+        .expect_commit_success();
+    let receipt = rebalance(&mut test_runner, &first_loan);
+    assert_eq!(
+        get_events::<StableRateRebalanceEvent>(&test_runner, &receipt, pool, "StableRateRebalanceEvent"),
+        vec![StableRateRebalanceEvent{ loan_id: first_loan.clone(), debt: dec!("50"), previous_rate: Decimal::ZERO, rate: dec!("0.16") }]
+    );
+    assert_eq!(stable_rate_of(&mut test_runner, &first_loan), dec!("0.16"));
+    // a loan already at the stable rate is left alone.
+    rebalance(&mut test_runner, &first_loan).expect_commit_failure();
+
+    // the deposit rate trigger, (300 * 0.16 + 50 * 0.16 + 50 * 0.01125) * 0.9 / 1000 >= 0.2 * 0.16.
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "set_rebalance_params", manifest_args!(dec!("0.95"), dec!("0.2")))
+        .expect_commit_success();
+    rebalance(&mut test_runner, &second_loan).expect_commit_success();
+    assert_eq!(stable_rate_of(&mut test_runner, &second_loan), dec!("0.16"));
+}
+
+#[test]
+fn test_rebalance_params_are_bounded() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (pool, _, pool_badge) = instantiate_lend_pool(&mut test_runner, package_address, &public_key, account);
+
+    for (rebalance_utilization, rebalance_deposit_ratio) in [
+        (Decimal::ZERO, Decimal::ONE),
+        (dec!("1.1"), Decimal::ONE),
+        (dec!("0.4"), Decimal::ZERO),
+        (dec!("0.4"), dec!("1.1"))
+    ] {
+        call_with_badge(
+            &mut test_runner, &public_key, account, pool_badge, pool, "set_rebalance_params",
+            manifest_args!(rebalance_utilization, rebalance_deposit_ratio)
+        ).expect_commit_failure();
+    }
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "set_rebalance_params", manifest_args!(dec!("0.4"), Decimal::ONE))
+        .expect_commit_success();
+
+    // only the pool owner sets them.
+    let manifest = ManifestBuilder::new()
+        .call_method(pool, "set_rebalance_params", manifest_args!(dec!("0.4"), Decimal::ONE))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(&public_key)],
+    ).expect_commit_failure();
+}
+
+fn swap_to_variable(
+    test_runner: &mut DefaultTestRunner,
+    public_key: &Secp256k1PublicKey,
+    account: ComponentAddress,
+    pool_badge: ResourceAddress,
+    pool: ComponentAddress,
+    loan_id: NonFungibleLocalId
+) -> TransactionReceipt {
+    let loan_token = call_and_decode::<ResourceAddress>(test_runner, pool, "get_stable_loan_token", manifest_args!());
+    let manifest = ManifestBuilder::new()
+        .create_proof_from_account_of_amount(account, pool_badge, dec!("1"))
+        .withdraw_non_fungibles_from_account(account, loan_token, [loan_id])
+        .take_all_from_worktop(loan_token, "receipt")
+        .call_method_with_name_lookup(pool, "swap_to_variable", |lookup| (lookup.bucket("receipt"),))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(public_key)],
+    )
+}
+
+#[test]
+fn test_lend_pool_emits_swap_events() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (pool, _, pool_badge) = instantiate_lend_pool(&mut test_runner, package_address, &public_key, account);
+    let loan_token = call_and_decode::<ResourceAddress>(&mut test_runner, pool, "get_stable_loan_token", manifest_args!());
+    deposit(&mut test_runner, &public_key, account, pool, dec!("1000")).expect_commit_success();
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "borrow_variable", manifest_args!(dec!("500")))
+        .expect_commit_success();
+
+    let receipt = call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "swap_to_stable", manifest_args!(dec!("500")));
+    let loan_id = get_received_non_fungible(&receipt, account, loan_token);
+    let loan = call_and_decode::<dse::pools::lending::StableLoanData>(&mut test_runner, pool, "get_stable_loan", manifest_args!(loan_id.clone()));
+    assert_eq!(
+        get_events::<SwapRateModeEvent>(&test_runner, &receipt, pool, "SwapRateModeEvent"),
+        vec![SwapRateModeEvent{ amount: dec!("500"), stable: true, rate: loan.rate }]
+    );
+
+    let receipt = swap_to_variable(&mut test_runner, &public_key, account, pool_badge, pool, loan_id);
+    let rate_update = get_events::<RateUpdateEvent>(&test_runner, &receipt, pool, "RateUpdateEvent");
+    assert_eq!(
+        get_events::<SwapRateModeEvent>(&test_runner, &receipt, pool, "SwapRateModeEvent"),
+        vec![SwapRateModeEvent{ amount: dec!("500"), stable: false, rate: rate_update[0].variable_rate }]
+    );
 }
 
 #[test]
-fn test_hello_with_test_environment() -> Result<(), RuntimeError> {
-    // Arrange
-    let mut env = TestEnvironment::new();
-    let package_address = Package::compile_and_publish(this_package!(), &mut env)?;
+fn test_swap_rate_mode_keeps_total_debt() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (pool, _, pool_badge) = instantiate_lend_pool(&mut test_runner, package_address, &public_key, account);
+    let loan_token = call_and_decode::<ResourceAddress>(&mut test_runner, pool, "get_stable_loan_token", manifest_args!());
+    deposit(&mut test_runner, &public_key, account, pool, dec!("1000")).expect_commit_success();
+
+    // 400 variable, then 100 stable at 0.2 * 0.4 + 0.5 * 0.4 ** 2.
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "borrow_variable", manifest_args!(dec!("400")))
+        .expect_commit_success();
+    let receipt = call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "borrow_stable", manifest_args!(dec!("100")));
+    let first_loan = get_received_non_fungible(&receipt, account, loan_token);
+    let loan_value = |test_runner: &mut DefaultTestRunner| call_and_decode::<Decimal>(test_runner, pool, "get_loan_value", manifest_args!());
+    let stable_total = |test_runner: &mut DefaultTestRunner| call_and_decode::<(Decimal, Decimal)>(test_runner, pool, "get_stable_loan_total", manifest_args!());
+    let variable_share = |test_runner: &mut DefaultTestRunner| call_and_decode::<Decimal>(test_runner, pool, "get_variable_share_quantity", manifest_args!());
+    assert_eq!(loan_value(&mut test_runner), dec!("500"));
+    assert_eq!(stable_total(&mut test_runner), (dec!("100"), dec!("0.16")));
 
-    let mut hello = Hello::instantiate_hello(package_address, &mut env)?;
+    // half of the variable debt becomes a stable loan priced at the utilization of 0.5.
+    let receipt = call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "swap_to_stable", manifest_args!(dec!("200")));
+    let second_loan = get_received_non_fungible(&receipt, account, loan_token);
+    let loan = call_and_decode::<dse::pools::lending::StableLoanData>(&mut test_runner, pool, "get_stable_loan", manifest_args!(second_loan.clone()));
+    assert_eq!((loan.loan_amount, loan.rate), (dec!("200"), dec!("0.225")));
+    assert_eq!(loan_value(&mut test_runner), dec!("500"));
+    assert_eq!(variable_share(&mut test_runner), dec!("200"));
+    let (stable_amount, stable_rate) = stable_total(&mut test_runner);
+    assert_eq!(stable_amount, dec!("300"));
+    assert_close(stable_rate, (dec!("16") + dec!("45")) / dec!("300"), dec!("0.000000000001"));
 
-    // Act
-    let bucket = hello.free_token(&mut env)?;
+    // the first loan goes back to variable, the weighted rate is that of the second one alone.
+    swap_to_variable(&mut test_runner, &public_key, account, pool_badge, pool, first_loan).expect_commit_success();
+    assert_eq!(loan_value(&mut test_runner), dec!("500"));
+    assert_eq!(variable_share(&mut test_runner), dec!("300"));
+    let (stable_amount, stable_rate) = stable_total(&mut test_runner);
+    assert_eq!(stable_amount, dec!("200"));
+    assert_close(stable_rate, dec!("0.225"), dec!("0.000000000001"));
+    assert_eq!(test_runner.get_component_balance(account, loan_token), Decimal::ONE);
 
-    // Assert
-    let amount = bucket.amount(&mut env)?;
-    assert_eq!(amount, dec!("1"));
+    // with interest accrued, the swap moves the debt without changing it.
+    let current_epoch = test_runner.get_current_epoch();
+    test_runner.set_current_epoch(current_epoch.after(100).unwrap());
+    let debt_before = loan_value(&mut test_runner);
+    assert!(debt_before > dec!("500"));
+    swap_to_variable(&mut test_runner, &public_key, account, pool_badge, pool, second_loan).expect_commit_success();
+    assert_close(loan_value(&mut test_runner), debt_before, dec!("0.000001"));
+    assert_close(stable_total(&mut test_runner).0, Decimal::ZERO, dec!("0.000001"));
+    assert_eq!(test_runner.get_component_balance(account, loan_token), Decimal::ZERO);
+}
+
+fn repay_stable(
+    test_runner: &mut DefaultTestRunner,
+    public_key: &Secp256k1PublicKey,
+    account: ComponentAddress,
+    pool_badge: ResourceAddress,
+    pool: ComponentAddress,
+    amount: Decimal,
+    loan_id: NonFungibleLocalId
+) -> TransactionReceipt {
+    let loan_token = call_and_decode::<ResourceAddress>(test_runner, pool, "get_stable_loan_token", manifest_args!());
+    let manifest = ManifestBuilder::new()
+        .create_proof_from_account_of_amount(account, pool_badge, dec!("1"))
+        .withdraw_from_account(account, XRD, amount)
+        .take_all_from_worktop(XRD, "repay")
+        .withdraw_non_fungibles_from_account(account, loan_token, [loan_id])
+        .take_all_from_worktop(loan_token, "receipt")
+        .call_method_with_name_lookup(pool, "repay_stable", |lookup| (lookup.bucket("repay"), lookup.bucket("receipt")))
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(public_key)],
+    )
+}
+
+#[test]
+fn test_repay_stable_partially_exactly_and_over() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (pool, _, pool_badge) = instantiate_lend_pool(&mut test_runner, package_address, &public_key, account);
+    let loan_token = call_and_decode::<ResourceAddress>(&mut test_runner, pool, "get_stable_loan_token", manifest_args!());
+    deposit(&mut test_runner, &public_key, account, pool, dec!("1000")).expect_commit_success();
+    // the variable debt keeps the stable rate above zero.
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "borrow_variable", manifest_args!(dec!("400")))
+        .expect_commit_success();
+    let receipt = call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "borrow_stable", manifest_args!(dec!("100")));
+    let loan_id = get_received_non_fungible(&receipt, account, loan_token);
+    let available = |test_runner: &mut DefaultTestRunner| call_and_decode::<Decimal>(test_runner, pool, "get_available", manifest_args!());
+    let debt_of = |test_runner: &mut DefaultTestRunner, loan_id: &NonFungibleLocalId| call_and_decode::<Decimal>(
+        test_runner, pool, "get_stable_loan_debt", manifest_args!(loan_id.clone())
+    );
+
+    // a partial repayment keeps the receipt with the rest of the debt.
+    let (vault_before, balance_before) = (available(&mut test_runner), test_runner.get_component_balance(account, XRD));
+    repay_stable(&mut test_runner, &public_key, account, pool_badge, pool, dec!("30"), loan_id.clone()).expect_commit_success();
+    assert_eq!(available(&mut test_runner), vault_before + dec!("30"));
+    assert_eq!(test_runner.get_component_balance(account, XRD), balance_before - dec!("30"));
+    assert_eq!(test_runner.get_component_balance(account, loan_token), Decimal::ONE);
+    assert_eq!(debt_of(&mut test_runner, &loan_id), dec!("70"));
+
+    // the exact debt with its interest burns the receipt.
+    let current_epoch = test_runner.get_current_epoch();
+    test_runner.set_current_epoch(current_epoch.after(100).unwrap());
+    let debt = debt_of(&mut test_runner, &loan_id);
+    assert!(debt > dec!("70"));
+    let (vault_before, balance_before) = (available(&mut test_runner), test_runner.get_component_balance(account, XRD));
+    repay_stable(&mut test_runner, &public_key, account, pool_badge, pool, debt, loan_id).expect_commit_success();
+    assert_eq!(available(&mut test_runner), vault_before + debt);
+    assert_eq!(test_runner.get_component_balance(account, XRD), balance_before - debt);
+    assert_eq!(test_runner.get_component_balance(account, loan_token), Decimal::ZERO);
+
+    // anything above the debt is returned.
+    let receipt = call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "borrow_stable", manifest_args!(dec!("50")));
+    let loan_id = get_received_non_fungible(&receipt, account, loan_token);
+    let current_epoch = test_runner.get_current_epoch();
+    test_runner.set_current_epoch(current_epoch.after(100).unwrap());
+    let debt = debt_of(&mut test_runner, &loan_id);
+    let (vault_before, balance_before) = (available(&mut test_runner), test_runner.get_component_balance(account, XRD));
+    let receipt = repay_stable(&mut test_runner, &public_key, account, pool_badge, pool, dec!("80"), loan_id);
+    assert_eq!(
+        get_events::<RepayEvent>(&test_runner, &receipt, pool, "RepayEvent"),
+        vec![RepayEvent{ amount: debt, stable: true }]
+    );
+    assert_eq!(available(&mut test_runner), vault_before + debt);
+    assert_eq!(test_runner.get_component_balance(account, XRD), balance_before - debt);
+    assert_eq!(test_runner.get_component_balance(account, loan_token), Decimal::ZERO);
+}
+
+#[test]
+fn test_repay_stable_keeps_the_average_rate_positive() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    // a flat kinked curve sets the stable rate, there is no validator APY nor premium on top.
+    let flat_curve = |base_rate: Decimal| KinkedParams { base_rate, optimal_utilization: dec!("0.8"), slope1: Decimal::ZERO, slope2: Decimal::ZERO };
+    let (interest_model, model_admin_badge) = instantiate_kinked_interest_model(&mut test_runner, package_address, &public_key, account, flat_curve(Decimal::ZERO));
+
+    let (pool, _, pool_badge) = instantiate_lend_pool_with_model(&mut test_runner, package_address, account, interest_model, InterestModel::Kinked);
+    let loan_token = call_and_decode::<ResourceAddress>(&mut test_runner, pool, "get_stable_loan_token", manifest_args!());
+    deposit(&mut test_runner, &public_key, account, pool, dec!("1000")).expect_commit_success();
+
+    // 100 at 0% and 100 at 20%.
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "borrow_stable", manifest_args!(dec!("100")))
+        .expect_commit_success();
+    call_with_badge(
+        &mut test_runner, &public_key, account, model_admin_badge, interest_model, "set_curve",
+        manifest_args!("kinked".to_owned(), InterestCurve::Kinked(flat_curve(dec!("0.2"))))
+    ).expect_commit_success();
+    let receipt = call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "borrow_stable", manifest_args!(dec!("100")));
+    let loan_id = get_received_non_fungible(&receipt, account, loan_token);
+    let loan = call_and_decode::<dse::pools::lending::StableLoanData>(&mut test_runner, pool, "get_stable_loan", manifest_args!(loan_id.clone()));
+    assert_eq!(loan.rate, dec!("0.2"));
+
+    // a year on, the 20% loan owes more interest than the total at the 10% average accounts for.
+    let current_epoch = test_runner.get_current_epoch();
+    test_runner.set_current_epoch(current_epoch.after(EPOCH_OF_YEAR).unwrap());
+    let debt = call_and_decode::<Decimal>(&mut test_runner, pool, "get_stable_loan_debt", manifest_args!(loan_id.clone()));
+    repay_stable(&mut test_runner, &public_key, account, pool_badge, pool, debt, loan_id).expect_commit_success();
+    let (stable_amount, stable_rate) = call_and_decode::<(Decimal, Decimal)>(&mut test_runner, pool, "get_stable_loan_total", manifest_args!());
+    assert!(stable_amount > Decimal::ZERO);
+    assert_eq!(stable_rate, Decimal::ZERO);
+}
+
+#[test]
+fn test_repay_stable_rejects_a_foreign_receipt() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (pool, _, pool_badge) = instantiate_lend_pool(&mut test_runner, package_address, &public_key, account);
+
+    // only the receipts minted by the pool carry the terms of a loan.
+    let foreign_receipt = test_runner.create_non_fungible_resource(account);
+    let manifest = ManifestBuilder::new()
+        .create_proof_from_account_of_amount(account, pool_badge, dec!("1"))
+        .withdraw_from_account(account, XRD, dec!("10"))
+        .take_all_from_worktop(XRD, "repay")
+        .withdraw_non_fungibles_from_account(account, foreign_receipt, [NonFungibleLocalId::integer(1)])
+        .take_all_from_worktop(foreign_receipt, "receipt")
+        .call_method_with_name_lookup(pool, "repay_stable", |lookup| (lookup.bucket("repay"), lookup.bucket("receipt")))
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(&public_key)],
+    ).expect_commit_failure();
+}
+
+fn repay_variable(
+    test_runner: &mut DefaultTestRunner,
+    public_key: &Secp256k1PublicKey,
+    account: ComponentAddress,
+    pool_badge: ResourceAddress,
+    pool: ComponentAddress,
+    amount: Decimal,
+    loan_share: Decimal
+) -> TransactionReceipt {
+    let manifest = ManifestBuilder::new()
+        .create_proof_from_account_of_amount(account, pool_badge, dec!("1"))
+        .withdraw_from_account(account, XRD, amount)
+        .take_all_from_worktop(XRD, "repay")
+        .call_method_with_name_lookup(pool, "repay_variable", |lookup| (lookup.bucket("repay"), loan_share))
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(public_key)],
+    )
+}
+
+#[test]
+fn test_repay_variable_partially_exactly_and_over() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (pool, _, pool_badge) = instantiate_lend_pool(&mut test_runner, package_address, &public_key, account);
+    deposit(&mut test_runner, &public_key, account, pool, dec!("1000")).expect_commit_success();
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "borrow_variable", manifest_args!(dec!("500")))
+        .expect_commit_success();
+    let available = |test_runner: &mut DefaultTestRunner| call_and_decode::<Decimal>(test_runner, pool, "get_available", manifest_args!());
+    let variable_share = |test_runner: &mut DefaultTestRunner| call_and_decode::<Decimal>(test_runner, pool, "get_variable_share_quantity", manifest_args!());
+
+    // a partial repayment burns the shares it covers.
+    let (vault_before, balance_before) = (available(&mut test_runner), test_runner.get_component_balance(account, XRD));
+    repay_variable(&mut test_runner, &public_key, account, pool_badge, pool, dec!("100"), dec!("500")).expect_commit_success();
+    assert_eq!(variable_share(&mut test_runner), dec!("400"));
+    assert_eq!(available(&mut test_runner), vault_before + dec!("100"));
+    assert_eq!(test_runner.get_component_balance(account, XRD), balance_before - dec!("100"));
+
+    // the exact debt of some shares, interest included.
+    let current_epoch = test_runner.get_current_epoch();
+    test_runner.set_current_epoch(current_epoch.after(100).unwrap());
+    let (_, loan_index) = call_and_decode::<(Decimal, Decimal)>(&mut test_runner, pool, "get_current_index", manifest_args!());
+    assert!(loan_index > Decimal::ONE);
+    let debt = dec!("150") * loan_index;
+    let (vault_before, balance_before) = (available(&mut test_runner), test_runner.get_component_balance(account, XRD));
+    repay_variable(&mut test_runner, &public_key, account, pool_badge, pool, debt, dec!("150")).expect_commit_success();
+    assert_eq!(variable_share(&mut test_runner), dec!("250"));
+    assert_eq!(available(&mut test_runner), vault_before + debt);
+    assert_eq!(test_runner.get_component_balance(account, XRD), balance_before - debt);
+
+    // anything above the debt is returned.
+    let debt = dec!("250") * loan_index;
+    let (vault_before, balance_before) = (available(&mut test_runner), test_runner.get_component_balance(account, XRD));
+    let receipt = repay_variable(&mut test_runner, &public_key, account, pool_badge, pool, dec!("300"), dec!("250"));
+    assert_eq!(
+        get_events::<RepayEvent>(&test_runner, &receipt, pool, "RepayEvent"),
+        vec![RepayEvent{ amount: debt, stable: false }]
+    );
+    assert_eq!(variable_share(&mut test_runner), Decimal::ZERO);
+    assert_eq!(available(&mut test_runner), vault_before + debt);
+    assert_eq!(test_runner.get_component_balance(account, XRD), balance_before - debt);
+
+    // no shares are left to repay.
+    repay_variable(&mut test_runner, &public_key, account, pool_badge, pool, dec!("10"), dec!("1")).expect_commit_failure();
+}
+
+#[test]
+fn test_repay_variable_needs_loan_shares() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (pool, _, pool_badge) = instantiate_lend_pool(&mut test_runner, package_address, &public_key, account);
+
+    // the shares to repay are positive and no more than the pool lent.
+    repay_variable(&mut test_runner, &public_key, account, pool_badge, pool, dec!("10"), Decimal::ZERO).expect_commit_failure();
+    repay_variable(&mut test_runner, &public_key, account, pool_badge, pool, dec!("10"), dec!("1")).expect_commit_failure();
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, pool, "get_available", manifest_args!()), Decimal::ZERO);
+}
+
+fn flash_loan(
+    test_runner: &mut DefaultTestRunner,
+    public_key: &Secp256k1PublicKey,
+    account: ComponentAddress,
+    pool: ComponentAddress,
+    amount: Decimal,
+    repay_amount: Decimal
+) -> TransactionReceipt {
+    let flash_loan_token = call_and_decode::<ResourceAddress>(test_runner, pool, "get_flash_loan_token", manifest_args!());
+    let manifest = ManifestBuilder::new()
+        .call_method(pool, "flash_borrow", manifest_args!(amount))
+        .take_all_from_worktop(XRD, "loan")
+        .call_method_with_name_lookup(account, "deposit", |lookup| (lookup.bucket("loan"),))
+        .withdraw_from_account(account, XRD, repay_amount)
+        .take_all_from_worktop(XRD, "repay")
+        .take_all_from_worktop(flash_loan_token, "receipt")
+        .call_method_with_name_lookup(pool, "flash_repay", |lookup| (lookup.bucket("repay"), lookup.bucket("receipt")))
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(public_key)],
+    )
+}
+
+#[test]
+fn test_flash_loan_must_be_repaid_with_fee() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (pool, _, pool_badge) = instantiate_lend_pool(&mut test_runner, package_address, &public_key, account);
+    deposit(&mut test_runner, &public_key, account, pool, dec!("1000")).expect_commit_success();
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "set_flash_loan_fee_ratio", manifest_args!(dec!("0.01")))
+        .expect_commit_success();
+
+    // the receipt can not be deposited, so a loan that is not repaid fails.
+    let manifest = ManifestBuilder::new()
+        .call_method(pool, "flash_borrow", manifest_args!(dec!("100")))
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(&public_key)],
+    ).expect_commit_failure();
+
+    // the fee of 1 is missing.
+    flash_loan(&mut test_runner, &public_key, account, pool, dec!("100"), dec!("100")).expect_commit_failure();
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, pool, "get_available", manifest_args!()), dec!("1000"));
+
+    // the pool keeps the fee and returns the rest.
+    let balance = test_runner.get_component_balance(account, XRD);
+    let receipt = flash_loan(&mut test_runner, &public_key, account, pool, dec!("100"), dec!("105"));
+    assert_eq!(
+        get_events::<FlashBorrowEvent>(&test_runner, &receipt, pool, "FlashBorrowEvent"),
+        vec![FlashBorrowEvent{ amount: dec!("100"), fee: dec!("1") }]
+    );
+    assert_eq!(
+        get_events::<FlashRepayEvent>(&test_runner, &receipt, pool, "FlashRepayEvent"),
+        vec![FlashRepayEvent{ amount: dec!("100"), fee: dec!("1") }]
+    );
+    assert_eq!(test_runner.get_component_balance(account, XRD), balance - dec!("1"));
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, pool, "get_available", manifest_args!()), dec!("1001"));
+
+    // 0.1 of the fee goes to the insurance, the rest to the 1000 deposit shares.
+    let (insurance, _) = call_and_decode::<(Decimal, Decimal)>(&mut test_runner, pool, "get_insurance", manifest_args!());
+    assert_eq!(insurance, dec!("0.1"));
+    let (deposit_index, loan_index) = call_and_decode::<(Decimal, Decimal)>(&mut test_runner, pool, "get_current_index", manifest_args!());
+    assert_eq!(deposit_index, dec!("1.0009"));
+    assert_eq!(loan_index, Decimal::ONE);
+}
+
+fn add_insurance(
+    test_runner: &mut DefaultTestRunner,
+    public_key: &Secp256k1PublicKey,
+    account: ComponentAddress,
+    pool_badge: ResourceAddress,
+    pool: ComponentAddress,
+    amount: Decimal
+) -> TransactionReceipt {
+    let manifest = ManifestBuilder::new()
+        .create_proof_from_account_of_amount(account, pool_badge, dec!("1"))
+        .withdraw_from_account(account, XRD, amount)
+        .take_all_from_worktop(XRD, "insurance")
+        .call_method_with_name_lookup(pool, "add_insurance", |lookup| (lookup.bucket("insurance"),))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(public_key)],
+    )
+}
+
+#[test]
+fn test_flash_loan_fee_goes_to_insurance_without_depositors() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (pool, _, pool_badge) = instantiate_lend_pool(&mut test_runner, package_address, &public_key, account);
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "set_flash_loan_fee_ratio", manifest_args!(Decimal::ONE))
+        .expect_commit_failure();
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "set_flash_loan_fee_ratio", manifest_args!(dec!("0.01")))
+        .expect_commit_success();
+
+    // the insurance is all the pool holds.
+    add_insurance(&mut test_runner, &public_key, account, pool_badge, pool, dec!("1000")).expect_commit_success();
+    flash_loan(&mut test_runner, &public_key, account, pool, dec!("1001"), dec!("1012")).expect_commit_failure();
+
+    let balance = test_runner.get_component_balance(account, XRD);
+    flash_loan(&mut test_runner, &public_key, account, pool, dec!("100"), dec!("101")).expect_commit_success();
+    assert_eq!(test_runner.get_component_balance(account, XRD), balance - dec!("1"));
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, pool, "get_available", manifest_args!()), dec!("1001"));
+    // with no deposit shares the whole fee goes to the insurance.
+    assert_eq!(call_and_decode::<(Decimal, Decimal)>(&mut test_runner, pool, "get_insurance", manifest_args!()), (dec!("1001"), dec!("0.1")));
+}
+
+#[test]
+fn test_supply_and_borrow_caps() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (pool, _, pool_badge) = instantiate_lend_pool(&mut test_runner, package_address, &public_key, account);
+
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "set_caps", manifest_args!(dec!("-1"), dec!("600")))
+        .expect_commit_failure();
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "set_caps", manifest_args!(dec!("1500"), dec!("600")))
+        .expect_commit_success();
+    assert_eq!(call_and_decode::<(Decimal, Decimal)>(&mut test_runner, pool, "get_caps", manifest_args!()), (dec!("1500"), dec!("600")));
+
+    deposit(&mut test_runner, &public_key, account, pool, dec!("1000")).expect_commit_success();
+    deposit(&mut test_runner, &public_key, account, pool, dec!("600")).expect_commit_failure();
+    deposit(&mut test_runner, &public_key, account, pool, dec!("500")).expect_commit_success();
+
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "borrow_variable", manifest_args!(dec!("400")))
+        .expect_commit_success();
+    // the cap counts the variable and the stable debt together.
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "borrow_stable", manifest_args!(dec!("201")))
+        .expect_commit_failure();
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "borrow_stable", manifest_args!(dec!("200")))
+        .expect_commit_success();
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "borrow_variable", manifest_args!(dec!("1")))
+        .expect_commit_failure();
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, pool, "get_loan_value", manifest_args!()), dec!("600"));
+}
+
+#[test]
+fn test_insurance_withdrawal_and_ratio() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (pool, _, pool_badge) = instantiate_lend_pool(&mut test_runner, package_address, &public_key, account);
+    deposit(&mut test_runner, &public_key, account, pool, dec!("1000")).expect_commit_success();
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "borrow_variable", manifest_args!(dec!("500")))
+        .expect_commit_success();
+
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "set_insurance_ratio", manifest_args!(Decimal::ONE))
+        .expect_commit_failure();
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "set_insurance_ratio", manifest_args!(dec!("-0.1")))
+        .expect_commit_failure();
+
+    // the change settles the interest so far at the old ratio: the variable rate 0.225 and the
+    // deposit rate 0.225 * 0.5 * 0.9, the spread is the insurance.
+    let current_epoch = test_runner.get_current_epoch();
+    test_runner.set_current_epoch(current_epoch.after(100).unwrap());
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "set_insurance_ratio", manifest_args!(dec!("0.5")))
+        .expect_commit_success();
+    assert_eq!(
+        call_and_decode::<u64>(&mut test_runner, pool, "get_last_update", manifest_args!()),
+        test_runner.get_current_epoch().number()
+    );
+    let (insurance, insurance_ratio) = call_and_decode::<(Decimal, Decimal)>(&mut test_runner, pool, "get_insurance", manifest_args!());
+    let loan_interest = dec!("500") * (calc_compound_growth(dec!("0.225"), 100, EPOCH_OF_YEAR) - Decimal::ONE);
+    let deposit_interest = dec!("1000") * (calc_compound_growth(dec!("0.10125"), 100, EPOCH_OF_YEAR) - Decimal::ONE);
+    assert_close(insurance, loan_interest - deposit_interest, dec!("0.000000000001"));
+    assert_eq!(insurance_ratio, dec!("0.5"));
+
+    // only the pool owner withdraws, and no more than the balance.
+    let manifest = ManifestBuilder::new()
+        .call_method(pool, "withdraw_insurance", manifest_args!(insurance))
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(&public_key)],
+    ).expect_commit_failure();
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "withdraw_insurance", manifest_args!(insurance + dec!("0.000001")))
+        .expect_commit_failure();
+    let available = call_and_decode::<Decimal>(&mut test_runner, pool, "get_available", manifest_args!());
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, pool, "withdraw_insurance", manifest_args!(insurance))
+        .expect_commit_success();
+    assert_eq!(call_and_decode::<(Decimal, Decimal)>(&mut test_runner, pool, "get_insurance", manifest_args!()).0, Decimal::ZERO);
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, pool, "get_available", manifest_args!()), available - insurance);
+
+    // through the controller, the insurance is only deposited to the treasury set by the admin.
+    let market = instantiate_lending_market(&mut test_runner, package_address, &public_key, account);
+    open_position_in_debt(&mut test_runner, &public_key, account, &market);
+    let current_epoch = test_runner.get_current_epoch();
+    test_runner.set_current_epoch(current_epoch.after(100).unwrap());
+    test_runner.execute_manifest_ignoring_fee(
+        ManifestBuilder::new().call_method(market.pool, "accrue_interest", manifest_args!()).build(),
+        vec![]
+    ).expect_commit_success();
+    let (insurance, _) = call_and_decode::<(Decimal, Decimal)>(&mut test_runner, market.pool, "get_insurance", manifest_args!());
+    assert!(insurance > Decimal::ZERO);
+    call_with_badge(&mut test_runner, &public_key, account, market.admin_badge, market.controller, "withdraw_insurance", manifest_args!(XRD, insurance))
+        .expect_commit_failure();
+
+    let (_, _, treasury) = test_runner.new_allocated_account();
+    let manifest = ManifestBuilder::new()
+        .call_method(market.controller, "set_treasury", manifest_args!(treasury))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(manifest, vec![]).expect_commit_failure();
+    call_with_badge(&mut test_runner, &public_key, account, market.admin_badge, market.controller, "set_treasury", manifest_args!(treasury))
+        .expect_commit_success();
+    assert_eq!(
+        call_and_decode::<Option<ComponentAddress>>(&mut test_runner, market.controller, "get_treasury", manifest_args!()),
+        Some(treasury)
+    );
+    let treasury_balance = test_runner.get_component_balance(treasury, XRD);
+    call_with_badge(&mut test_runner, &public_key, account, market.admin_badge, market.controller, "withdraw_insurance", manifest_args!(XRD, insurance))
+        .expect_commit_success();
+    assert_eq!(test_runner.get_component_balance(treasury, XRD), treasury_balance + insurance);
+    assert_eq!(call_and_decode::<(Decimal, Decimal)>(&mut test_runner, market.pool, "get_insurance", manifest_args!()).0, Decimal::ZERO);
+}
+
+fn new_validator_accepting_stake(
+    test_runner: &mut DefaultTestRunner,
+    public_key: &Secp256k1PublicKey,
+    account: ComponentAddress
+) -> ComponentAddress {
+    let validator = test_runner.new_validator_with_pub_key(public_key.clone(), account);
+    let manifest = ManifestBuilder::new()
+        .create_proof_from_account_of_non_fungibles(
+            account,
+            VALIDATOR_OWNER_BADGE,
+            [NonFungibleLocalId::bytes(validator.as_node_id().0).unwrap()],
+        )
+        .call_method(validator, "update_accept_delegated_stake", manifest_args!(true))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(public_key)],
+    ).expect_commit_success();
+    validator
+}
+
+#[test]
+fn test_keeper_emits_validator_snapshot() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (keeper, admin_badge) = instantiate_keeper(&mut test_runner, package_address, &public_key, account);
+    let validator = new_validator_accepting_stake(&mut test_runner, &public_key, account);
+
+    let manifest = ManifestBuilder::new()
+        .create_proof_from_account_of_amount(account, admin_badge, dec!("1"))
+        .call_method(keeper, "log_validator_staking", manifest_args!(vec![validator], Vec::<ComponentAddress>::new()))
+        .build();
+    let receipt = test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(&public_key)],
+    );
+    let snapshots = get_events::<ValidatorSnapshotEvent>(&test_runner, &receipt, keeper, "ValidatorSnapshotEvent");
+    assert_eq!(snapshots.len(), 1);
+    assert_eq!(snapshots[0].validator, validator);
+    assert_eq!(snapshots[0].epoch_at, test_runner.get_current_epoch().number());
+}
+
+fn instantiate_staking_pool(
+    test_runner: &mut DefaultTestRunner,
+    package_address: PackageAddress,
+    public_key: &Secp256k1PublicKey,
+    account: ComponentAddress,
+    validators: Vec<ComponentAddress>
+) -> (ComponentAddress, ResourceAddress, ResourceAddress) {
+    let (staking_pool, staking_share_token, pool_badge, _, _) = instantiate_staking_pool_with_keeper(
+        test_runner, package_address, public_key, account, validators
+    );
+    (staking_pool, staking_share_token, pool_badge)
+}
+
+fn instantiate_staking_pool_with_keeper(
+    test_runner: &mut DefaultTestRunner,
+    package_address: PackageAddress,
+    public_key: &Secp256k1PublicKey,
+    account: ComponentAddress,
+    validators: Vec<ComponentAddress>
+) -> (ComponentAddress, ResourceAddress, ResourceAddress, ComponentAddress, ResourceAddress) {
+    let (keeper, keeper_badge) = instantiate_keeper(test_runner, package_address, public_key, account);
+    let manifest = ManifestBuilder::new()
+        .create_proof_from_account_of_amount(account, keeper_badge, dec!("1"))
+        .call_method(keeper, "log_validator_staking", manifest_args!(validators, Vec::<ComponentAddress>::new()))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(public_key)],
+    ).expect_commit_success();
+
+    let pool_badge = test_runner.create_fungible_resource(dec!("1"), 0, account);
+    let pool_mgr_rule = rule!(require(pool_badge));
+    let manifest = ManifestBuilder::new()
+        .call_function(
+            package_address,
+            "StakingResourePool",
+            "instantiate",
+            manifest_args!(XRD, keeper, OwnerRole::Fixed(pool_mgr_rule.clone()), pool_mgr_rule, None::<ManifestAddressReservation>),
+        )
+        .build();
+    let receipt = test_runner.execute_manifest_ignoring_fee(manifest, vec![]);
+    let (staking_pool, staking_share_token) = receipt.expect_commit_success().output::<(ComponentAddress, ResourceAddress)>(0);
+    (staking_pool, staking_share_token, pool_badge, keeper, keeper_badge)
+}
+
+fn contribute(
+    test_runner: &mut DefaultTestRunner,
+    public_key: &Secp256k1PublicKey,
+    account: ComponentAddress,
+    staking_pool: ComponentAddress,
+    amount: Decimal,
+    validator: Option<ComponentAddress>
+) -> TransactionReceipt {
+    let builder = ManifestBuilder::new()
+        .withdraw_from_account(account, XRD, amount)
+        .take_all_from_worktop(XRD, "stake");
+    let builder = match validator {
+        Some(validator) => builder.call_method_with_name_lookup(staking_pool, "contribute", |lookup| (lookup.bucket("stake"), validator)),
+        None => builder.call_method_with_name_lookup(staking_pool, "contribute_auto", |lookup| (lookup.bucket("stake"),))
+    };
+    let manifest = builder
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(public_key)],
+    )
+}
+
+#[test]
+fn test_staking_pool_emits_stake_event() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let validator = new_validator_accepting_stake(&mut test_runner, &public_key, account);
+    let (staking_pool, _, _) = instantiate_staking_pool(&mut test_runner, package_address, &public_key, account, vec![validator]);
+
+    let receipt = contribute(&mut test_runner, &public_key, account, staking_pool, dec!("100"), Some(validator));
+    assert_eq!(
+        get_events::<StakeEvent>(&test_runner, &receipt, staking_pool, "StakeEvent"),
+        vec![StakeEvent{ validator, amount: dec!("100"), share_amount: dec!("100") }]
+    );
+}
+
+#[test]
+fn test_staking_pool_prices_an_empty_pool_at_one() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (staking_pool, staking_share_token, pool_badge) = instantiate_staking_pool(&mut test_runner, package_address, &public_key, account, vec![]);
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, staking_pool, "get_value_per_share", manifest_args!()), Decimal::ONE);
+
+    // the buffer takes the whole contribution, redeeming all of it at once leaves the max fee of 10 in the pool.
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, staking_pool, "set_buffer_params", manifest_args!(dec!("1"), dec!("0.001"), dec!("0.05")))
+        .expect_commit_success();
+    contribute(&mut test_runner, &public_key, account, staking_pool, dec!("200"), None).expect_commit_success();
+    let manifest = ManifestBuilder::new()
+        .withdraw_from_account(account, staking_share_token, dec!("200"))
+        .take_all_from_worktop(staking_share_token, "shares")
+        .call_method_with_name_lookup(staking_pool, "redeem_instant", |lookup| (lookup.bucket("shares"),))
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(&public_key)],
+    ).expect_commit_success();
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, staking_pool, "get_vault_amount", manifest_args!()), dec!("10"));
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, staking_pool, "get_value_per_share", manifest_args!()), Decimal::ONE);
+
+    // the next contributor does not take the fee left in the pool.
+    contribute(&mut test_runner, &public_key, account, staking_pool, dec!("10"), None).expect_commit_success();
+    assert_eq!(test_runner.get_component_balance(account, staking_share_token), dec!("10"));
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, staking_pool, "get_value_per_share", manifest_args!()), Decimal::ONE);
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, staking_pool, "get_redemption_value", manifest_args!(dec!("10"))), dec!("10"));
+}
+
+#[test]
+fn test_kinked_interest_model() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (keeper, _) = instantiate_keeper(&mut test_runner, package_address, &public_key, account);
+    let interest_model = instantiate_interest_model(&mut test_runner, package_address, &public_key, account, keeper, default_kinked_params())
+        .expect_commit(true).new_component_addresses()[0];
+
+    let mut variable_rate = |borrow_ratio: Decimal| call_and_decode::<Decimal>(
+        &mut test_runner, interest_model, "get_variable_interest_rate", manifest_args!(borrow_ratio, InterestModel::Kinked)
+    );
+    assert_eq!(variable_rate(Decimal::ZERO), dec!("0.02"));
+    assert_eq!(variable_rate(dec!("0.4")), dec!("0.04"));
+    // the kink.
+    assert_eq!(variable_rate(dec!("0.8")), dec!("0.06"));
+    assert_eq!(variable_rate(dec!("0.9")), dec!("0.36"));
+    assert_eq!(variable_rate(Decimal::ONE), dec!("0.66"));
+    assert_eq!(variable_rate(dec!("1.5")), dec!("0.66"));
+
+    let mut invalid = default_kinked_params();
+    invalid.optimal_utilization = Decimal::ONE;
+    instantiate_interest_model(&mut test_runner, package_address, &public_key, account, keeper, invalid).expect_commit_failure();
+    let mut invalid = default_kinked_params();
+    invalid.slope2 = dec!("-0.1");
+    instantiate_interest_model(&mut test_runner, package_address, &public_key, account, keeper, invalid).expect_commit_failure();
+}
+
+#[test]
+fn test_named_interest_curves() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (interest_model, admin_badge) = instantiate_kinked_interest_model(&mut test_runner, package_address, &public_key, account, default_kinked_params());
+
+    let set_curve = |test_runner: &mut DefaultTestRunner, name: &str, curve: InterestCurve, with_badge: bool| {
+        let mut builder = ManifestBuilder::new();
+        if with_badge {
+            builder = builder.create_proof_from_account_of_amount(account, admin_badge, dec!("1"));
+        }
+        let manifest = builder
+            .call_method(interest_model, "set_curve", manifest_args!(name.to_owned(), curve))
+            .build();
+        test_runner.execute_manifest_ignoring_fee(
+            manifest,
+            vec![NonFungibleGlobalId::from_public_key(&public_key)],
+        )
+    };
+    let xrd_curve = InterestCurve::Quadratic{ primary: dec!("0.1"), quadratic: dec!("0.3") };
+    set_curve(&mut test_runner, "xrd", xrd_curve, true).expect_commit_success();
+
+    let rate_of = |test_runner: &mut DefaultTestRunner, model: InterestModel| call_and_decode::<Decimal>(
+        test_runner, interest_model, "get_variable_interest_rate", manifest_args!(dec!("0.5"), model)
+    );
+    // 0.1 * 0.5 + 0.3 * 0.25, while the default curve keeps 0.2 * 0.5 + 0.5 * 0.25.
+    assert_eq!(rate_of(&mut test_runner, InterestModel::Named("xrd".to_owned())), dec!("0.125"));
+    assert_eq!(rate_of(&mut test_runner, InterestModel::Default), dec!("0.225"));
+
+    // retuning the default curve leaves the named one alone.
+    let default_curve = InterestCurve::Quadratic{ primary: dec!("0.4"), quadratic: Decimal::ZERO };
+    set_curve(&mut test_runner, "default", default_curve, true).expect_commit_success();
+    assert_eq!(rate_of(&mut test_runner, InterestModel::Default), dec!("0.2"));
+    assert_eq!(rate_of(&mut test_runner, InterestModel::Named("xrd".to_owned())), dec!("0.125"));
+
+    let negative_curve = InterestCurve::Quadratic{ primary: dec!("-0.1"), quadratic: Decimal::ZERO };
+    set_curve(&mut test_runner, "xrd", negative_curve, true).expect_commit_failure();
+    let unauthorized_curve = InterestCurve::Quadratic{ primary: dec!("0.1"), quadratic: Decimal::ZERO };
+    set_curve(&mut test_runner, "xrd", unauthorized_curve, false).expect_commit_failure();
+
+    let manifest = ManifestBuilder::new()
+        .call_method(interest_model, "get_variable_interest_rate", manifest_args!(dec!("0.5"), InterestModel::Named("btc".to_owned())))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(manifest, vec![]).expect_commit_failure();
+}
+
+#[test]
+fn test_interest_curve_update_with_timelock() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (interest_model, admin_badge) = instantiate_kinked_interest_model(&mut test_runner, package_address, &public_key, account, default_kinked_params());
+
+    let rate_of = |test_runner: &mut DefaultTestRunner, model: InterestModel| call_and_decode::<Decimal>(
+        test_runner, interest_model, "get_variable_interest_rate", manifest_args!(dec!("0.5"), model)
+    );
+
+    // without a timelock the change applies at once.
+    call_with_badge(&mut test_runner, &public_key, account, admin_badge, interest_model, "set_coefficient", manifest_args!("default".to_owned(), CurveCoefficient::Quadratic, Decimal::ZERO))
+        .expect_commit_success();
+    assert_eq!(rate_of(&mut test_runner, InterestModel::Default), dec!("0.1"));
+
+    call_with_badge(&mut test_runner, &public_key, account, admin_badge, interest_model, "set_timelock", manifest_args!(100u64)).expect_commit_success();
+    call_with_badge(&mut test_runner, &public_key, account, admin_badge, interest_model, "set_coefficient", manifest_args!("default".to_owned(), CurveCoefficient::Primary, dec!("0.4")))
+        .expect_commit_success();
+    assert_eq!(rate_of(&mut test_runner, InterestModel::Default), dec!("0.1"));
+
+    let current_epoch = test_runner.get_current_epoch();
+    test_runner.set_current_epoch(current_epoch.after(99).unwrap());
+    assert_eq!(rate_of(&mut test_runner, InterestModel::Default), dec!("0.1"));
+    test_runner.set_current_epoch(current_epoch.after(100).unwrap());
+    assert_eq!(rate_of(&mut test_runner, InterestModel::Default), dec!("0.2"));
+
+    // a cancelled change never takes effect.
+    call_with_badge(&mut test_runner, &public_key, account, admin_badge, interest_model, "set_coefficient", manifest_args!("default".to_owned(), CurveCoefficient::Primary, dec!("0.8")))
+        .expect_commit_success();
+    call_with_badge(&mut test_runner, &public_key, account, admin_badge, interest_model, "cancel_pending_curve", manifest_args!("default".to_owned())).expect_commit_success();
+    test_runner.set_current_epoch(current_epoch.after(300).unwrap());
+    assert_eq!(rate_of(&mut test_runner, InterestModel::Default), dec!("0.2"));
+
+    // out of bounds, or not a coefficient of the curve.
+    call_with_badge(&mut test_runner, &public_key, account, admin_badge, interest_model, "set_coefficient", manifest_args!("default".to_owned(), CurveCoefficient::Primary, dec!("11")))
+        .expect_commit_failure();
+    call_with_badge(&mut test_runner, &public_key, account, admin_badge, interest_model, "set_coefficient", manifest_args!("default".to_owned(), CurveCoefficient::Slope1, dec!("0.1")))
+        .expect_commit_failure();
+    call_with_badge(&mut test_runner, &public_key, account, admin_badge, interest_model, "set_coefficient", manifest_args!("kinked".to_owned(), CurveCoefficient::OptimalUtilization, Decimal::ONE))
+        .expect_commit_failure();
+
+    let manifest = ManifestBuilder::new()
+        .call_method(interest_model, "set_timelock", manifest_args!(0u64))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(manifest, vec![]).expect_commit_failure();
+}
+
+#[test]
+fn test_lowered_timelock_waits_for_the_current_one() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (interest_model, admin_badge) = instantiate_kinked_interest_model(&mut test_runner, package_address, &public_key, account, default_kinked_params());
+
+    let rate_of = |test_runner: &mut DefaultTestRunner| call_and_decode::<Decimal>(
+        test_runner, interest_model, "get_variable_interest_rate", manifest_args!(dec!("0.5"), InterestModel::Default)
+    );
+
+    // raising applies at once.
+    call_with_badge(&mut test_runner, &public_key, account, admin_badge, interest_model, "set_timelock", manifest_args!(100u64)).expect_commit_success();
+    assert_eq!(call_and_decode::<u64>(&mut test_runner, interest_model, "get_timelock", manifest_args!()), 100u64);
+
+    // lowering waits for the current timelock, so a curve change right after it is still locked.
+    call_with_badge(&mut test_runner, &public_key, account, admin_badge, interest_model, "set_timelock", manifest_args!(0u64)).expect_commit_success();
+    assert_eq!(call_and_decode::<u64>(&mut test_runner, interest_model, "get_timelock", manifest_args!()), 100u64);
+    call_with_badge(&mut test_runner, &public_key, account, admin_badge, interest_model, "set_coefficient", manifest_args!("default".to_owned(), CurveCoefficient::Quadratic, Decimal::ZERO))
+        .expect_commit_success();
+    assert_eq!(rate_of(&mut test_runner), dec!("0.225"));
+
+    let current_epoch = test_runner.get_current_epoch();
+    test_runner.set_current_epoch(current_epoch.after(99).unwrap());
+    assert_eq!(rate_of(&mut test_runner), dec!("0.225"));
+    assert_eq!(call_and_decode::<u64>(&mut test_runner, interest_model, "get_timelock", manifest_args!()), 100u64);
+
+    test_runner.set_current_epoch(current_epoch.after(100).unwrap());
+    assert_eq!(rate_of(&mut test_runner), dec!("0.1"));
+    assert_eq!(call_and_decode::<u64>(&mut test_runner, interest_model, "get_timelock", manifest_args!()), 0u64);
+
+    // from then on changes apply at once.
+    call_with_badge(&mut test_runner, &public_key, account, admin_badge, interest_model, "set_coefficient", manifest_args!("default".to_owned(), CurveCoefficient::Primary, dec!("0.4")))
+        .expect_commit_success();
+    assert_eq!(rate_of(&mut test_runner), dec!("0.2"));
+}
+
+#[derive(ManifestSbor, Clone)]
+struct AdaptiveParams {
+    target_utilization: Decimal,
+    gain: Decimal,
+    min_rate: Decimal,
+    max_rate: Decimal,
+    max_step: Decimal
+}
+
+fn instantiate_adaptive_interest_model(
+    test_runner: &mut DefaultTestRunner,
+    package_address: PackageAddress,
+    public_key: &Secp256k1PublicKey,
+    account: ComponentAddress
+) -> (ComponentAddress, ResourceAddress) {
+    let manifest = ManifestBuilder::new()
+        .call_function(
+            package_address,
+            "AdaptiveInterestModel",
+            "instantiate",
+            manifest_args!(dec!("0.02")),
+        )
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    let receipt = test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(public_key)],
+    );
+    let commit = receipt.expect_commit(true);
+    (commit.new_component_addresses()[0], commit.new_resource_addresses()[0])
+}
+
+#[test]
+fn test_adaptive_interest_model_targets_utilization() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let pool_badge = test_runner.create_fungible_resource(dec!("1"), 0, account);
+
+    let (interest_model, admin_badge) = instantiate_adaptive_interest_model(&mut test_runner, package_address, &public_key, account);
+
+    let params = AdaptiveParams {
+        target_utilization: dec!("0.8"),
+        gain: dec!("0.5"),
+        min_rate: dec!("0.01"),
+        max_rate: dec!("1"),
+        max_step: dec!("0.01")
+    };
+    let manifest = ManifestBuilder::new()
+        .create_proof_from_account_of_amount(account, admin_badge, dec!("1"))
+        .call_method(interest_model, "set_adaptive_params", manifest_args!("xrd".to_owned(), params.clone(), dec!("0.05")))
+        .call_method(interest_model, "set_reporter", manifest_args!("xrd".to_owned(), rule!(require(pool_badge))))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(&public_key)],
+    ).expect_commit_success();
+
+    let report = |test_runner: &mut DefaultTestRunner, borrow_ratio: Decimal| {
+        let manifest = ManifestBuilder::new()
+            .create_proof_from_account_of_amount(account, pool_badge, dec!("1"))
+            .call_method(interest_model, "report_borrow_ratio", manifest_args!(borrow_ratio, InterestModel::Adaptive("xrd".to_owned())))
+            .build();
+        test_runner.execute_manifest_ignoring_fee(
+            manifest,
+            vec![NonFungibleGlobalId::from_public_key(&public_key)],
+        ).expect_commit_success();
+    };
+    let variable_rate = |test_runner: &mut DefaultTestRunner| call_and_decode::<Decimal>(
+        test_runner, interest_model, "get_variable_interest_rate", manifest_args!(Decimal::ZERO, InterestModel::Adaptive("xrd".to_owned()))
+    );
+    let current_epoch = test_runner.get_current_epoch();
+
+    // the reported ratio only drives the rate from the next epoch on.
+    report(&mut test_runner, dec!("0.9"));
+    assert_eq!(variable_rate(&mut test_runner), dec!("0.05"));
+    // above the target, the step 0.5 * 0.1 is bounded by the max step.
+    test_runner.set_current_epoch(current_epoch.after(1).unwrap());
+    assert_eq!(variable_rate(&mut test_runner), dec!("0.06"));
+    test_runner.set_current_epoch(current_epoch.after(11).unwrap());
+    assert_eq!(variable_rate(&mut test_runner), dec!("0.16"));
+    // reading does not settle the rate, the ratio of the last report still drives it.
+    let state = call_and_decode::<dse::interest::AdaptiveState>(&mut test_runner, interest_model, "get_state", manifest_args!("xrd".to_owned()));
+    assert_eq!((state.rate, state.last_epoch), (dec!("0.05"), current_epoch.number()));
+    report(&mut test_runner, dec!("0.81"));
+    // 0.5 * 0.01 a epoch.
+    test_runner.set_current_epoch(current_epoch.after(13).unwrap());
+    let stable_rate = call_and_decode::<Decimal>(
+        &mut test_runner, interest_model, "get_stable_interest_rate", manifest_args!(Decimal::ZERO, Decimal::ZERO, InterestModel::Adaptive("xrd".to_owned()))
+    );
+    assert_eq!(stable_rate, dec!("0.17") + dec!("0.02"));
+    report(&mut test_runner, dec!("0.5"));
+    // below the target the rate falls, down to the min rate.
+    test_runner.set_current_epoch(current_epoch.after(15).unwrap());
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, interest_model, "get_current_rate", manifest_args!("xrd".to_owned())), dec!("0.15"));
+    test_runner.set_current_epoch(current_epoch.after(1000).unwrap());
+    assert_eq!(variable_rate(&mut test_runner), dec!("0.01"));
+
+    // only the reporter may report.
+    let manifest = ManifestBuilder::new()
+        .call_method(interest_model, "report_borrow_ratio", manifest_args!(dec!("1"), InterestModel::Adaptive("xrd".to_owned())))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(manifest, vec![]).expect_commit_failure();
+
+    // nor may the reporter of another state.
+    let other_badge = test_runner.create_fungible_resource(dec!("1"), 0, account);
+    let manifest = ManifestBuilder::new()
+        .create_proof_from_account_of_amount(account, admin_badge, dec!("1"))
+        .call_method(interest_model, "set_adaptive_params", manifest_args!("other".to_owned(), params, dec!("0.05")))
+        .call_method(interest_model, "set_reporter", manifest_args!("other".to_owned(), rule!(require(other_badge))))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(&public_key)],
+    ).expect_commit_success();
+    let manifest = ManifestBuilder::new()
+        .create_proof_from_account_of_amount(account, other_badge, dec!("1"))
+        .call_method(interest_model, "report_borrow_ratio", manifest_args!(dec!("1"), InterestModel::Adaptive("xrd".to_owned())))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(&public_key)],
+    ).expect_commit_failure();
+}
+
+#[test]
+fn test_adaptive_interest_model_drives_lend_pool() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (interest_model, admin_badge) = instantiate_adaptive_interest_model(&mut test_runner, package_address, &public_key, account);
+
+    let (pool, _, pool_badge) = instantiate_lend_pool_with_model(&mut test_runner, package_address, account, interest_model, InterestModel::Adaptive("xrd".to_owned()));
+
+    let params = AdaptiveParams {
+        target_utilization: dec!("0.8"),
+        gain: dec!("0.5"),
+        min_rate: dec!("0.01"),
+        max_rate: dec!("1"),
+        max_step: dec!("0.01")
+    };
+    let manifest = ManifestBuilder::new()
+        .create_proof_from_account_of_amount(account, admin_badge, dec!("1"))
+        .call_method(interest_model, "set_adaptive_params", manifest_args!("xrd".to_owned(), params, dec!("0.05")))
+        .call_method(interest_model, "set_reporter", manifest_args!("xrd".to_owned(), rule!(require(global_caller(pool)))))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(&public_key)],
+    ).expect_commit_success();
+
+    deposit(&mut test_runner, &public_key, account, pool, dec!("1000")).expect_commit_success();
+    let manifest = ManifestBuilder::new()
+        .create_proof_from_account_of_amount(account, pool_badge, dec!("1"))
+        .call_method(pool, "borrow_variable", manifest_args!(dec!("900")))
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    let receipt = test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(&public_key)],
+    );
+    assert_eq!(get_events::<RateUpdateEvent>(&test_runner, &receipt, pool, "RateUpdateEvent")[0].variable_rate, dec!("0.05"));
+    let borrow_epoch = test_runner.get_current_epoch();
+    let state = call_and_decode::<dse::interest::AdaptiveState>(&mut test_runner, interest_model, "get_state", manifest_args!("xrd".to_owned()));
+    assert_eq!((state.borrow_ratio, state.last_epoch), (dec!("0.9"), borrow_epoch.number()));
+
+    // the views of the pool read the rate without settling it.
+    test_runner.set_current_epoch(borrow_epoch.after(10).unwrap());
+    let (variable_rate, _, _) = call_and_decode::<(Decimal, Decimal, Decimal)>(&mut test_runner, pool, "get_interest_rate", manifest_args!());
+    assert_eq!(variable_rate, dec!("0.15"));
+    let state = call_and_decode::<dse::interest::AdaptiveState>(&mut test_runner, interest_model, "get_state", manifest_args!("xrd".to_owned()));
+    assert_eq!(state.last_epoch, borrow_epoch.number());
+
+    // accruing reports the borrow ratio, which grew with the interest.
+    let receipt = test_runner.execute_manifest_ignoring_fee(
+        ManifestBuilder::new().call_method(pool, "accrue_interest", manifest_args!()).build(),
+        vec![]
+    );
+    assert_eq!(get_events::<RateUpdateEvent>(&test_runner, &receipt, pool, "RateUpdateEvent")[0].variable_rate, dec!("0.15"));
+    let state = call_and_decode::<dse::interest::AdaptiveState>(&mut test_runner, interest_model, "get_state", manifest_args!("xrd".to_owned()));
+    assert_eq!((state.rate, state.last_epoch), (dec!("0.15"), borrow_epoch.number() + 10));
+    assert!(state.borrow_ratio > dec!("0.9"));
+
+    // the model only takes reports from the pool.
+    let manifest = ManifestBuilder::new()
+        .create_proof_from_account_of_amount(account, pool_badge, dec!("1"))
+        .call_method(interest_model, "report_borrow_ratio", manifest_args!(dec!("0.1"), InterestModel::Adaptive("xrd".to_owned())))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(&public_key)],
+    ).expect_commit_failure();
+}
+
+#[test]
+fn test_stable_premium_at_edge_ratios() {
+    let premium = dse::interest::StablePremium {
+        stable_ratio_slope: dec!("0.1"),
+        utilization_threshold: dec!("0.8"),
+        utilization_slope: dec!("0.5")
+    };
+    premium.validate();
+
+    // no stable debt and utilization below the threshold.
+    assert_eq!(premium.get_premium(Decimal::ZERO, Decimal::ZERO), Decimal::ZERO);
+    assert_eq!(premium.get_premium(dec!("0.8"), Decimal::ZERO), Decimal::ZERO);
+    // all debt is stable.
+    assert_eq!(premium.get_premium(Decimal::ZERO, Decimal::ONE), dec!("0.1"));
+    assert_eq!(premium.get_premium(dec!("0.5"), Decimal::ONE), dec!("0.1"));
+    // above the threshold the utilization part grows to the full slope at 1.
+    assert_eq!(premium.get_premium(dec!("0.9"), Decimal::ZERO), dec!("0.25"));
+    assert_eq!(premium.get_premium(Decimal::ONE, Decimal::ZERO), dec!("0.5"));
+    assert_eq!(premium.get_premium(Decimal::ONE, Decimal::ONE), dec!("0.6"));
+    // ratios above 1 are capped.
+    assert_eq!(premium.get_premium(dec!("1.2"), dec!("1.5")), dec!("0.6"));
+    // it grows with the stable share.
+    assert!(premium.get_premium(dec!("0.9"), dec!("0.6")) > premium.get_premium(dec!("0.9"), dec!("0.3")));
+}
+
+#[test]
+#[should_panic]
+fn test_stable_premium_rejects_threshold_of_one() {
+    dse::interest::StablePremium {
+        stable_ratio_slope: dec!("0.1"),
+        utilization_threshold: Decimal::ONE,
+        utilization_slope: dec!("0.5")
+    }.validate();
+}
+
+#[derive(ManifestSbor)]
+struct StablePremium {
+    stable_ratio_slope: Decimal,
+    utilization_threshold: Decimal,
+    utilization_slope: Decimal
+}
+
+#[test]
+fn test_stable_premium_change_waits_for_timelock() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (interest_model, admin_badge) = instantiate_kinked_interest_model(&mut test_runner, package_address, &public_key, account, default_kinked_params());
+
+    let stable_rate = |test_runner: &mut DefaultTestRunner| call_and_decode::<Decimal>(
+        test_runner, interest_model, "get_stable_interest_rate", manifest_args!(dec!("0.5"), Decimal::ONE, InterestModel::Default)
+    );
+    // no validator is tracked, so the base is the variable rate 0.2 * 0.5 + 0.5 * 0.25.
+    assert_eq!(stable_rate(&mut test_runner), dec!("0.225"));
+
+    call_with_badge(&mut test_runner, &public_key, account, admin_badge, interest_model, "set_timelock", manifest_args!(100u64)).expect_commit_success();
+    let premium = StablePremium{
+        stable_ratio_slope: dec!("0.1"),
+        utilization_threshold: dec!("0.8"),
+        utilization_slope: dec!("0.5")
+    };
+    call_with_badge(&mut test_runner, &public_key, account, admin_badge, interest_model, "set_stable_premium", manifest_args!(premium)).expect_commit_success();
+    assert_eq!(stable_rate(&mut test_runner), dec!("0.225"));
+
+    let current_epoch = test_runner.get_current_epoch();
+    test_runner.set_current_epoch(current_epoch.after(99).unwrap());
+    assert_eq!(stable_rate(&mut test_runner), dec!("0.225"));
+    test_runner.set_current_epoch(current_epoch.after(100).unwrap());
+    assert_eq!(stable_rate(&mut test_runner), dec!("0.325"));
+
+    let invalid = StablePremium{
+        stable_ratio_slope: dec!("0.1"),
+        utilization_threshold: Decimal::ONE,
+        utilization_slope: dec!("0.5")
+    };
+    call_with_badge(&mut test_runner, &public_key, account, admin_badge, interest_model, "set_stable_premium", manifest_args!(invalid)).expect_commit_failure();
+}
+
+#[test]
+fn test_allocation_strategies() {
+    use dse::pools::staking::{allocate, AllocationStrategy};
+    let validators = [
+        (1u8, dec!("0.1"), dec!("50")),
+        (2u8, dec!("0.3"), dec!("30")),
+        (3u8, Decimal::ZERO, dec!("20"))
+    ];
+
+    let equal = allocate(AllocationStrategy::EqualWeight, dec!("100"), &validators);
+    assert_eq!(equal.len(), 3);
+    assert_eq!(equal[0], (1u8, dec!("33.333333333333333333")));
+    assert_eq!(equal.iter().fold(Decimal::ZERO, |sum, (_, amount)| sum + *amount), dec!("100"));
+
+    // the validator without a known APY gets nothing.
+    assert_eq!(
+        allocate(AllocationStrategy::ApyWeighted, dec!("100"), &validators),
+        vec![(1u8, dec!("25")), (2u8, dec!("75"))]
+    );
+    let unknown_apy = [(1u8, Decimal::ZERO, dec!("50")), (2u8, Decimal::ZERO, dec!("50"))];
+    assert_eq!(
+        allocate(AllocationStrategy::ApyWeighted, dec!("100"), &unknown_apy),
+        vec![(1u8, dec!("50")), (2u8, dec!("50"))]
+    );
+
+    // the validator with half of the stake is over the cap.
+    assert_eq!(
+        allocate(AllocationStrategy::StakeShareCapped(dec!("0.4")), dec!("100"), &validators),
+        vec![(2u8, dec!("50")), (3u8, dec!("50"))]
+    );
+}
+
+#[test]
+fn test_contribute_auto_splits_stake() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (other_public_key, _other_private_key, other_account) = test_runner.new_allocated_account();
+    let validator = new_validator_accepting_stake(&mut test_runner, &public_key, account);
+    let other_validator = new_validator_accepting_stake(&mut test_runner, &other_public_key, other_account);
+    let (staking_pool, staking_share_token, _) = instantiate_staking_pool(
+        &mut test_runner, package_address, &public_key, account, vec![validator, other_validator]
+    );
+
+    let receipt = contribute(&mut test_runner, &public_key, account, staking_pool, dec!("100"), None);
+    let mut stakes = get_events::<StakeEvent>(&test_runner, &receipt, staking_pool, "StakeEvent");
+    stakes.sort_by_key(|stake| if stake.validator == validator { 0 } else { 1 });
+    assert_eq!(stakes.len(), 2);
+    assert_eq!((stakes[0].validator, stakes[0].amount), (validator, dec!("50")));
+    assert_eq!((stakes[1].validator, stakes[1].amount), (other_validator, dec!("50")));
+    assert_eq!(
+        get_events::<ContributeEvent>(&test_runner, &receipt, staking_pool, "ContributeEvent"),
+        vec![ContributeEvent{ amount: dec!("100"), buffered: Decimal::ZERO, staked: dec!("100"), share_amount: dec!("100") }]
+    );
+
+    // the shares are minted once, for the whole contribution.
+    assert_eq!(test_runner.get_component_balance(account, staking_share_token), dec!("100"));
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, staking_pool, "get_vault_amount", manifest_args!()), dec!("100"));
+}
+
+#[test]
+fn test_contribute_auto_needs_a_validator_only_to_stake() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (staking_pool, staking_share_token, pool_badge) = instantiate_staking_pool(&mut test_runner, package_address, &public_key, account, vec![]);
+
+    // there is XRD to stake but no validator to take it.
+    contribute(&mut test_runner, &public_key, account, staking_pool, dec!("10"), None).expect_commit_failure();
+
+    // the buffer takes it all.
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, staking_pool, "set_buffer_params", manifest_args!(dec!("1"), dec!("0.001"), dec!("0.05")))
+        .expect_commit_success();
+    contribute(&mut test_runner, &public_key, account, staking_pool, dec!("10"), None).expect_commit_success();
+    assert_eq!(test_runner.get_component_balance(account, staking_share_token), dec!("10"));
+    assert_eq!(
+        call_and_decode::<(Decimal, Decimal)>(&mut test_runner, staking_pool, "get_buffer", manifest_args!()),
+        (dec!("10"), dec!("10"))
+    );
+}
+
+#[test]
+fn test_rebalance_moves_stake_through_pending_claims() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (other_public_key, _other_private_key, other_account) = test_runner.new_allocated_account();
+    let validator = new_validator_accepting_stake(&mut test_runner, &public_key, account);
+    let other_validator = new_validator_accepting_stake(&mut test_runner, &other_public_key, other_account);
+    let (staking_pool, _, pool_badge) = instantiate_staking_pool(
+        &mut test_runner, package_address, &public_key, account, vec![validator, other_validator]
+    );
+    contribute(&mut test_runner, &public_key, account, staking_pool, dec!("100"), Some(validator)).expect_commit_success();
+
+    let rebalance = |test_runner: &mut DefaultTestRunner| {
+        let manifest = ManifestBuilder::new()
+            .create_proof_from_account_of_amount(account, pool_badge, dec!("1"))
+            .call_method(staking_pool, "rebalance", manifest_args!())
+            .build();
+        test_runner.execute_manifest_ignoring_fee(
+            manifest,
+            vec![NonFungibleGlobalId::from_public_key(&public_key)],
+        )
+    };
+
+    // half of the stake is over the equal weight target and gets unstaked.
+    rebalance(&mut test_runner).expect_commit_success();
+    let pending_claims = call_and_decode::<Vec<dse::pools::staking::PendingClaim>>(&mut test_runner, staking_pool, "get_pending_claims", manifest_args!());
+    assert_eq!(pending_claims.len(), 1);
+    assert_eq!(pending_claims[0].validator, validator);
+    assert_eq!(pending_claims[0].claim_amount, dec!("50"));
+    // the XRD being unstaked still counts to the pool value.
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, staking_pool, "get_vault_amount", manifest_args!()), dec!("100"));
+
+    // after the unbonding the claimed XRD is restaked to the under-weighted validator.
+    test_runner.set_current_epoch(Epoch::of(pending_claims[0].claim_epoch));
+    let receipt = rebalance(&mut test_runner);
+    assert_eq!(
+        get_events::<RestakeEvent>(&test_runner, &receipt, staking_pool, "RestakeEvent"),
+        vec![RestakeEvent{ validator: other_validator, amount: dec!("50") }]
+    );
+    // no shares are minted, so it is no stake event.
+    assert!(get_events::<StakeEvent>(&test_runner, &receipt, staking_pool, "StakeEvent").is_empty());
+    assert!(call_and_decode::<Vec<dse::pools::staking::PendingClaim>>(&mut test_runner, staking_pool, "get_pending_claims", manifest_args!()).is_empty());
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, staking_pool, "get_vault_amount", manifest_args!()), dec!("100"));
+
+    // rebalancing is restricted.
+    let manifest = ManifestBuilder::new()
+        .call_method(staking_pool, "rebalance", manifest_args!())
+        .build();
+    test_runner.execute_manifest_ignoring_fee(manifest, vec![]).expect_commit_failure();
+}
+
+#[test]
+fn test_rebalance_leaves_the_buffer_out() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (other_public_key, _other_private_key, other_account) = test_runner.new_allocated_account();
+    let validator = new_validator_accepting_stake(&mut test_runner, &public_key, account);
+    let other_validator = new_validator_accepting_stake(&mut test_runner, &other_public_key, other_account);
+    let (staking_pool, _, pool_badge) = instantiate_staking_pool(
+        &mut test_runner, package_address, &public_key, account, vec![validator, other_validator]
+    );
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, staking_pool, "set_buffer_params", manifest_args!(dec!("0.2"), dec!("0.001"), dec!("0.05")))
+        .expect_commit_success();
+    contribute(&mut test_runner, &public_key, account, staking_pool, dec!("100"), Some(validator)).expect_commit_success();
+
+    // 80 is staked and split equally, the buffer of 20 is not.
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, staking_pool, "rebalance", manifest_args!())
+        .expect_commit_success();
+    let pending_claims = call_and_decode::<Vec<dse::pools::staking::PendingClaim>>(&mut test_runner, staking_pool, "get_pending_claims", manifest_args!());
+    assert_eq!(pending_claims.len(), 1);
+    assert_eq!(pending_claims[0].claim_amount, dec!("40"));
+
+    // the buffer is at its target, so all the claimed XRD is restaked.
+    test_runner.set_current_epoch(Epoch::of(pending_claims[0].claim_epoch));
+    let receipt = call_with_badge(&mut test_runner, &public_key, account, pool_badge, staking_pool, "rebalance", manifest_args!());
+    assert_eq!(
+        get_events::<RestakeEvent>(&test_runner, &receipt, staking_pool, "RestakeEvent"),
+        vec![RestakeEvent{ validator: other_validator, amount: dec!("40") }]
+    );
+    assert_eq!(
+        call_and_decode::<(Decimal, Decimal)>(&mut test_runner, staking_pool, "get_buffer", manifest_args!()),
+        (dec!("20"), dec!("20"))
+    );
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, staking_pool, "get_vault_amount", manifest_args!()), dec!("100"));
+}
+
+#[test]
+fn test_rebalance_excludes_validators_by_fee_and_uptime() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (fee_public_key, _fee_private_key, fee_account) = test_runner.new_allocated_account();
+    let (uptime_public_key, _uptime_private_key, uptime_account) = test_runner.new_allocated_account();
+    let validator = new_validator_accepting_stake(&mut test_runner, &public_key, account);
+    let high_fee_validator = new_validator_accepting_stake(&mut test_runner, &fee_public_key, fee_account);
+    let low_uptime_validator = new_validator_accepting_stake(&mut test_runner, &uptime_public_key, uptime_account);
+    let (staking_pool, _, pool_badge, keeper, keeper_badge) = instantiate_staking_pool_with_keeper(
+        &mut test_runner, package_address, &public_key, account, vec![validator, high_fee_validator, low_uptime_validator]
+    );
+    for (validator_addr, fee, uptime) in [
+        (validator, dec!("0.01"), Decimal::ONE),
+        (high_fee_validator, dec!("0.2"), Decimal::ONE),
+        (low_uptime_validator, dec!("0.01"), dec!("0.9"))
+    ] {
+        call_with_badge(&mut test_runner, &public_key, account, keeper_badge, keeper, "log_validator_performance", manifest_args!(validator_addr, fee, uptime))
+            .expect_commit_success();
+    }
+    call_with_badge(&mut test_runner, &public_key, account, keeper_badge, keeper, "log_validator_performance", manifest_args!(validator, dec!("1.1"), Decimal::ONE))
+        .expect_commit_failure();
+
+    // the rules are bounded and only the pool owner sets them.
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, staking_pool, "set_validator_rules", manifest_args!(dec!("0.1"), dec!("1.1")))
+        .expect_commit_failure();
+    let manifest = ManifestBuilder::new()
+        .call_method(staking_pool, "set_validator_rules", manifest_args!(dec!("0.1"), dec!("0.95")))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(manifest, vec![]).expect_commit_failure();
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, staking_pool, "set_validator_rules", manifest_args!(dec!("0.1"), dec!("0.95")))
+        .expect_commit_success();
+    assert_eq!(
+        call_and_decode::<(Decimal, Decimal)>(&mut test_runner, staking_pool, "get_validator_rules", manifest_args!()),
+        (dec!("0.1"), dec!("0.95"))
+    );
+
+    contribute(&mut test_runner, &public_key, account, staking_pool, dec!("90"), Some(high_fee_validator)).expect_commit_success();
+    contribute(&mut test_runner, &public_key, account, staking_pool, dec!("90"), Some(low_uptime_validator)).expect_commit_success();
+
+    // the fee excludes one validator and the uptime the other, all their stake is unstaked.
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, staking_pool, "rebalance", manifest_args!())
+        .expect_commit_success();
+    let pending_claims = call_and_decode::<Vec<dse::pools::staking::PendingClaim>>(&mut test_runner, staking_pool, "get_pending_claims", manifest_args!());
+    assert_eq!(pending_claims.len(), 2);
+    for excluded_validator in [high_fee_validator, low_uptime_validator] {
+        let pending_claim = pending_claims.iter().find(|claim| claim.validator == excluded_validator).unwrap();
+        assert_eq!(pending_claim.claim_amount, dec!("90"));
+    }
+
+    // the claimed XRD all goes to the validator meeting the rules.
+    test_runner.set_current_epoch(Epoch::of(pending_claims[0].claim_epoch));
+    let receipt = call_with_badge(&mut test_runner, &public_key, account, pool_badge, staking_pool, "rebalance", manifest_args!());
+    assert_eq!(
+        get_events::<RestakeEvent>(&test_runner, &receipt, staking_pool, "RestakeEvent"),
+        vec![RestakeEvent{ validator, amount: dec!("180") }]
+    );
+}
+
+#[test]
+fn test_contribute_reports_the_buffered_part() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let validator = new_validator_accepting_stake(&mut test_runner, &public_key, account);
+    let (staking_pool, staking_share_token, pool_badge) = instantiate_staking_pool(&mut test_runner, package_address, &public_key, account, vec![validator]);
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, staking_pool, "set_buffer_params", manifest_args!(dec!("0.2"), dec!("0.001"), dec!("0.05")))
+        .expect_commit_success();
+
+    // the stake event only gets the shares of the staked part.
+    let receipt = contribute(&mut test_runner, &public_key, account, staking_pool, dec!("100"), Some(validator));
+    assert_eq!(
+        get_events::<ContributeEvent>(&test_runner, &receipt, staking_pool, "ContributeEvent"),
+        vec![ContributeEvent{ amount: dec!("100"), buffered: dec!("20"), staked: dec!("80"), share_amount: dec!("100") }]
+    );
+    assert_eq!(
+        get_events::<StakeEvent>(&test_runner, &receipt, staking_pool, "StakeEvent"),
+        vec![StakeEvent{ validator, amount: dec!("80"), share_amount: dec!("80") }]
+    );
+
+    // the buffer takes it all, shares are still minted and reported.
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, staking_pool, "set_buffer_params", manifest_args!(dec!("1"), dec!("0.001"), dec!("0.05")))
+        .expect_commit_success();
+    let receipt = contribute(&mut test_runner, &public_key, account, staking_pool, dec!("10"), Some(validator));
+    assert_eq!(
+        get_events::<ContributeEvent>(&test_runner, &receipt, staking_pool, "ContributeEvent"),
+        vec![ContributeEvent{ amount: dec!("10"), buffered: dec!("10"), staked: Decimal::ZERO, share_amount: dec!("10") }]
+    );
+    assert!(get_events::<StakeEvent>(&test_runner, &receipt, staking_pool, "StakeEvent").is_empty());
+    assert_eq!(test_runner.get_component_balance(account, staking_share_token), dec!("110"));
+}
+
+#[test]
+fn test_withdrawal_ticket_claims_after_unbonding() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let validator = new_validator_accepting_stake(&mut test_runner, &public_key, account);
+    let (staking_pool, staking_share_token, _) = instantiate_staking_pool(&mut test_runner, package_address, &public_key, account, vec![validator]);
+    contribute(&mut test_runner, &public_key, account, staking_pool, dec!("100"), Some(validator)).expect_commit_success();
+    let ticket_token = call_and_decode::<ResourceAddress>(&mut test_runner, staking_pool, "get_ticket_token", manifest_args!());
+
+    let manifest = ManifestBuilder::new()
+        .withdraw_from_account(account, staking_share_token, dec!("40"))
+        .take_all_from_worktop(staking_share_token, "shares")
+        .call_method_with_name_lookup(staking_pool, "redeem_ticket", |lookup| (lookup.bucket("shares"), validator))
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    let receipt = test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(&public_key)],
+    );
+    let redeems = get_events::<RedeemEvent>(&test_runner, &receipt, staking_pool, "RedeemEvent");
+    assert_eq!(redeems.len(), 1);
+    assert_eq!(redeems[0].validator, Some(validator));
+    assert_eq!((redeems[0].share_amount, redeems[0].redeem_value, redeems[0].fee), (dec!("40"), dec!("40"), Decimal::ZERO));
+    assert!(redeems[0].claim_nft_id.is_some());
+    // the shares are burnt and the pool keeps the claim NFT.
+    assert_eq!(test_runner.get_component_balance(account, staking_share_token), dec!("60"));
+    assert_eq!(test_runner.get_component_balance(account, ticket_token), dec!("1"));
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, staking_pool, "get_vault_amount", manifest_args!()), dec!("60"));
+
+    let claim = |test_runner: &mut DefaultTestRunner| {
+        let manifest = ManifestBuilder::new()
+            .withdraw_from_account(account, ticket_token, dec!("1"))
+            .take_all_from_worktop(ticket_token, "ticket")
+            .call_method_with_name_lookup(staking_pool, "claim_ticket", |lookup| (lookup.bucket("ticket"),))
+            .assert_worktop_contains(XRD, dec!("40"))
+            .call_method(
+                account,
+                "deposit_batch",
+                manifest_args!(ManifestExpression::EntireWorktop),
+            )
+            .build();
+        test_runner.execute_manifest_ignoring_fee(
+            manifest,
+            vec![NonFungibleGlobalId::from_public_key(&public_key)],
+        )
+    };
+    claim(&mut test_runner).expect_commit_failure();
+
+    let current_epoch = test_runner.get_current_epoch();
+    test_runner.set_current_epoch(current_epoch.after(1000).unwrap());
+    claim(&mut test_runner).expect_commit_success();
+    assert_eq!(test_runner.get_component_balance(account, ticket_token), Decimal::ZERO);
+}
+
+#[test]
+fn test_redeem_instant_from_buffer() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let validator = new_validator_accepting_stake(&mut test_runner, &public_key, account);
+    let (staking_pool, staking_share_token, pool_badge) = instantiate_staking_pool(&mut test_runner, package_address, &public_key, account, vec![validator]);
+
+    let manifest = ManifestBuilder::new()
+        .create_proof_from_account_of_amount(account, pool_badge, dec!("1"))
+        .call_method(staking_pool, "set_buffer_params", manifest_args!(dec!("0.2"), dec!("0.001"), dec!("0.05")))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(&public_key)],
+    ).expect_commit_success();
+
+    // a fifth of the contribution stays in the buffer.
+    contribute(&mut test_runner, &public_key, account, staking_pool, dec!("100"), Some(validator)).expect_commit_success();
+    assert_eq!(
+        call_and_decode::<(Decimal, Decimal)>(&mut test_runner, staking_pool, "get_buffer", manifest_args!()),
+        (dec!("20"), dec!("20"))
+    );
+
+    // paying 10 leaves the buffer half way below the target.
+    assert_eq!(
+        call_and_decode::<Decimal>(&mut test_runner, staking_pool, "get_instant_fee_ratio", manifest_args!(dec!("10"))),
+        dec!("0.0255")
+    );
+    // the fee rises as the buffer depletes.
+    assert!(
+        call_and_decode::<Decimal>(&mut test_runner, staking_pool, "get_instant_fee_ratio", manifest_args!(dec!("15")))
+        > dec!("0.0255")
+    );
+
+    let redeem_instant = |test_runner: &mut DefaultTestRunner, amount: Decimal| {
+        let manifest = ManifestBuilder::new()
+            .withdraw_from_account(account, staking_share_token, amount)
+            .take_all_from_worktop(staking_share_token, "shares")
+            .call_method_with_name_lookup(staking_pool, "redeem_instant", |lookup| (lookup.bucket("shares"),))
+            .call_method(
+                account,
+                "deposit_batch",
+                manifest_args!(ManifestExpression::EntireWorktop),
+            )
+            .build();
+        test_runner.execute_manifest_ignoring_fee(
+            manifest,
+            vec![NonFungibleGlobalId::from_public_key(&public_key)],
+        )
+    };
+    let receipt = redeem_instant(&mut test_runner, dec!("10"));
+    assert_eq!(
+        get_events::<RedeemEvent>(&test_runner, &receipt, staking_pool, "RedeemEvent"),
+        vec![RedeemEvent{ validator: None, share_amount: dec!("10"), redeem_value: dec!("10"), fee: dec!("0.255"), claim_nft_id: None }]
+    );
+
+    // the fee of 0.255 stays with the remaining holders.
+    let (buffer, _) = call_and_decode::<(Decimal, Decimal)>(&mut test_runner, staking_pool, "get_buffer", manifest_args!());
+    assert_eq!(buffer, dec!("10.255"));
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, staking_pool, "get_vault_amount", manifest_args!()), dec!("90.255"));
+    assert!(call_and_decode::<Decimal>(&mut test_runner, staking_pool, "get_value_per_share", manifest_args!()) > Decimal::ONE);
+
+    // more than the buffer holds.
+    redeem_instant(&mut test_runner, dec!("20")).expect_commit_failure();
+}
+
+// mirrors `dse::controller::RiskParams` for the manifests and the outputs.
+#[derive(ManifestSbor, ScryptoSbor, PartialEq, Eq, Debug, Clone)]
+struct RiskParams {
+    ltv: Decimal,
+    liquidation_threshold: Decimal,
+    liquidation_bonus: Decimal,
+    supply_cap: Decimal,
+    borrow_cap: Decimal,
+    collateral_enabled: bool,
+    borrow_enabled: bool,
+    stable_borrow_enabled: bool
+}
+
+fn risk_params(ltv: Decimal, liquidation_threshold: Decimal, liquidation_bonus: Decimal) -> RiskParams {
+    RiskParams {
+        ltv,
+        liquidation_threshold,
+        liquidation_bonus,
+        supply_cap: Decimal::MAX,
+        borrow_cap: Decimal::MAX,
+        collateral_enabled: true,
+        borrow_enabled: true,
+        stable_borrow_enabled: true
+    }
+}
+
+struct LendingMarket {
+    controller: ComponentAddress,
+    admin_badge: ResourceAddress,
+    interest_model: ComponentAddress,
+    pool: ComponentAddress,
+    deposit_share_token: ResourceAddress,
+    oracle: ComponentAddress,
+    oracle_admin_badge: ResourceAddress,
+    collateral: ResourceAddress,
+    cdp: ComponentAddress,
+    cdp_admin_badge: ResourceAddress,
+    cdp_token: ResourceAddress
+}
+
+// the badge of the new resources the account holds.
+fn find_badge(test_runner: &mut DefaultTestRunner, account: ComponentAddress, resources: &[ResourceAddress]) -> ResourceAddress {
+    *resources.iter()
+        .find(|res_addr| test_runner.get_component_balance(account, **res_addr) == Decimal::ONE)
+        .unwrap()
+}
+
+// an XRD pool with 2000 deposited, and a collateral token worth 10 XRD at 0.6 ltv, 0.8 liquidation threshold
+// and 0.1 bonus. The CDP manager quotes in XRD, with the close factor 0.5 and the liquidation insurance ratio 0.2.
+fn instantiate_lending_market(
+    test_runner: &mut DefaultTestRunner,
+    package_address: PackageAddress,
+    public_key: &Secp256k1PublicKey,
+    account: ComponentAddress
+) -> LendingMarket {
+    let (interest_model, _) = instantiate_kinked_interest_model(test_runner, package_address, public_key, account, default_kinked_params());
+
+    let manifest = ManifestBuilder::new()
+        .call_function(package_address, "LendingController", "instantiate", manifest_args!())
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    let receipt = test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(public_key)],
+    );
+    let commit = receipt.expect_commit(true);
+    let controller = commit.new_component_addresses()[0];
+    let new_resources = commit.new_resource_addresses().clone();
+    let admin_badge = find_badge(test_runner, account, &new_resources);
+
+    let receipt = call_with_badge(
+        test_runner, public_key, account, admin_badge, controller, "new_pool",
+        manifest_args!(XRD, interest_model, InterestModel::Default, dec!("0.1"), risk_params(dec!("0.5"), dec!("0.7"), dec!("0.05")))
+    );
+    let (pool, deposit_share_token) = receipt.expect_commit_success().output::<(ComponentAddress, ResourceAddress)>(1);
+
+    let collateral = test_runner.create_fungible_resource(dec!("1000"), 18, account);
+    call_with_badge(
+        test_runner, public_key, account, admin_badge, controller, "set_risk_params",
+        manifest_args!(collateral, risk_params(dec!("0.6"), dec!("0.8"), dec!("0.1")))
+    ).expect_commit_success();
+
+    let (oracle, oracle_admin_badge) = instantiate_oracle(test_runner, package_address, public_key, account);
+    set_price(test_runner, public_key, oracle, XRD, Decimal::ONE).expect_commit_success();
+    set_price(test_runner, public_key, oracle, collateral, dec!("10")).expect_commit_success();
+
+    let manifest = ManifestBuilder::new()
+        .call_function(
+            package_address,
+            "CollateralDebtManager",
+            "instantiate",
+            manifest_args!(controller, oracle, XRD, dec!("0.5"), dec!("0.2")),
+        )
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    let receipt = test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(public_key)],
+    );
+    let commit = receipt.expect_commit(true);
+    let cdp = commit.new_component_addresses()[0];
+    let new_resources = commit.new_resource_addresses().clone();
+    let cdp_admin_badge = find_badge(test_runner, account, &new_resources);
+    let cdp_token = *new_resources.iter().find(|res_addr| **res_addr != cdp_admin_badge).unwrap();
+
+    let manifest = ManifestBuilder::new()
+        .create_proof_from_account_of_amount(account, admin_badge, dec!("1"))
+        .set_role(controller, ModuleId::Main, "cdp_mgr", rule!(require(global_caller(cdp))))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(public_key)],
+    ).expect_commit_success();
+
+    deposit(test_runner, public_key, account, pool, dec!("2000")).expect_commit_success();
+
+    LendingMarket {
+        controller, admin_badge, interest_model, pool, deposit_share_token, oracle, oracle_admin_badge, collateral, cdp, cdp_admin_badge, cdp_token
+    }
+}
+
+fn open_position(
+    test_runner: &mut DefaultTestRunner,
+    public_key: &Secp256k1PublicKey,
+    account: ComponentAddress,
+    market: &LendingMarket,
+    amount: Decimal
+) -> NonFungibleLocalId {
+    let manifest = ManifestBuilder::new()
+        .withdraw_from_account(account, market.collateral, amount)
+        .take_all_from_worktop(market.collateral, "collateral")
+        .call_method_with_name_lookup(market.cdp, "open_position", |lookup| (lookup.bucket("collateral"),))
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    let receipt = test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(public_key)],
+    );
+    get_received_non_fungible(&receipt, account, market.cdp_token)
+}
+
+// calls the CDP manager with the position proof named "cdp", anything returned goes to the account.
+fn call_with_cdp<T: ResolvableArguments>(
+    test_runner: &mut DefaultTestRunner,
+    public_key: &Secp256k1PublicKey,
+    account: ComponentAddress,
+    market: &LendingMarket,
+    cdp_id: &NonFungibleLocalId,
+    method: &str,
+    args: impl FnOnce(&ManifestNameLookup) -> T
+) -> TransactionReceipt {
+    let manifest = ManifestBuilder::new()
+        .create_proof_from_account_of_non_fungibles(account, market.cdp_token, [cdp_id.clone()])
+        .pop_from_auth_zone("cdp")
+        .call_method_with_name_lookup(market.cdp, method, args)
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(public_key)],
+    )
+}
+
+// pays the amount of the resource to a CDP manager method taking the position proof and a bucket.
+fn pay_with_cdp(
+    test_runner: &mut DefaultTestRunner,
+    public_key: &Secp256k1PublicKey,
+    account: ComponentAddress,
+    market: &LendingMarket,
+    cdp_id: &NonFungibleLocalId,
+    method: &str,
+    (res_addr, amount): (ResourceAddress, Decimal)
+) -> TransactionReceipt {
+    let manifest = ManifestBuilder::new()
+        .withdraw_from_account(account, res_addr, amount)
+        .take_all_from_worktop(res_addr, "payment")
+        .create_proof_from_account_of_non_fungibles(account, market.cdp_token, [cdp_id.clone()])
+        .pop_from_auth_zone("cdp")
+        .call_method_with_name_lookup(market.cdp, method, |lookup| (lookup.proof("cdp"), lookup.bucket("payment")))
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(public_key)],
+    )
+}
+
+fn get_health_factor(test_runner: &mut DefaultTestRunner, market: &LendingMarket, cdp_id: &NonFungibleLocalId) -> Decimal {
+    call_and_decode::<Decimal>(test_runner, market.cdp, "get_health_factor", manifest_args!(cdp_id.clone()))
+}
+
+#[test]
+fn test_cdp_position_lifecycle() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let market = instantiate_lending_market(&mut test_runner, package_address, &public_key, account);
+    let collateral = market.collateral;
+
+    // 100 collateral is worth 1000, so the position borrows up to 600.
+    let cdp_id = open_position(&mut test_runner, &public_key, account, &market, dec!("100"));
+    assert_eq!(test_runner.get_component_balance(account, collateral), dec!("900"));
+    assert_eq!(get_health_factor(&mut test_runner, &market, &cdp_id), Decimal::MAX);
+
+    call_with_cdp(&mut test_runner, &public_key, account, &market, &cdp_id, "borrow_variable",
+        |lookup| (lookup.proof("cdp"), XRD, dec!("601"))).expect_commit_failure();
+    call_with_cdp(&mut test_runner, &public_key, account, &market, &cdp_id, "borrow_variable",
+        |lookup| (lookup.proof("cdp"), XRD, dec!("500"))).expect_commit_success();
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, market.pool, "get_loan_value", manifest_args!()), dec!("500"));
+    // the liquidation threshold values the collateral at 800.
+    assert_eq!(get_health_factor(&mut test_runner, &market, &cdp_id), dec!("1.6"));
+
+    // removing 20 lowers the borrowing limit to 480.
+    call_with_cdp(&mut test_runner, &public_key, account, &market, &cdp_id, "remove_collateral",
+        |lookup| (lookup.proof("cdp"), collateral, dec!("20"))).expect_commit_failure();
+    call_with_cdp(&mut test_runner, &public_key, account, &market, &cdp_id, "remove_collateral",
+        |lookup| (lookup.proof("cdp"), collateral, dec!("10"))).expect_commit_success();
+    assert_eq!(test_runner.get_component_balance(account, collateral), dec!("910"));
+    assert_eq!(get_health_factor(&mut test_runner, &market, &cdp_id), dec!("1.44"));
+
+    pay_with_cdp(&mut test_runner, &public_key, account, &market, &cdp_id, "add_collateral", (collateral, dec!("10"))).expect_commit_success();
+    assert_eq!(test_runner.get_component_balance(account, collateral), dec!("900"));
+    assert_eq!(get_health_factor(&mut test_runner, &market, &cdp_id), dec!("1.6"));
+
+    pay_with_cdp(&mut test_runner, &public_key, account, &market, &cdp_id, "repay_variable", (XRD, dec!("200"))).expect_commit_success();
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, market.pool, "get_loan_value", manifest_args!()), dec!("300"));
+    // the change of an over repayment goes back to the account.
+    pay_with_cdp(&mut test_runner, &public_key, account, &market, &cdp_id, "repay_variable", (XRD, dec!("400"))).expect_commit_success();
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, market.pool, "get_loan_value", manifest_args!()), Decimal::ZERO);
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, market.pool, "get_available", manifest_args!()), dec!("2000"));
+    assert_eq!(get_health_factor(&mut test_runner, &market, &cdp_id), Decimal::MAX);
+
+    // without debt the whole collateral comes out, but no more.
+    call_with_cdp(&mut test_runner, &public_key, account, &market, &cdp_id, "remove_collateral",
+        |lookup| (lookup.proof("cdp"), collateral, dec!("101"))).expect_commit_failure();
+    call_with_cdp(&mut test_runner, &public_key, account, &market, &cdp_id, "remove_collateral",
+        |lookup| (lookup.proof("cdp"), collateral, dec!("100"))).expect_commit_success();
+    assert_eq!(test_runner.get_component_balance(account, collateral), dec!("1000"));
+}
+
+#[test]
+fn test_cdp_prices_staking_shares_by_their_value() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let market = instantiate_lending_market(&mut test_runner, package_address, &public_key, account);
+    let (staking_pool, staking_share_token, pool_badge) = instantiate_staking_pool(&mut test_runner, package_address, &public_key, account, vec![]);
+
+    // redeeming half of the shares at once leaves a fee of 2.55 in the pool, a share is worth 1.0255.
+    call_with_badge(&mut test_runner, &public_key, account, pool_badge, staking_pool, "set_buffer_params", manifest_args!(dec!("1"), dec!("0.001"), dec!("0.05")))
+        .expect_commit_success();
+    contribute(&mut test_runner, &public_key, account, staking_pool, dec!("200"), None).expect_commit_success();
+    let manifest = ManifestBuilder::new()
+        .withdraw_from_account(account, staking_share_token, dec!("100"))
+        .take_all_from_worktop(staking_share_token, "shares")
+        .call_method_with_name_lookup(staking_pool, "redeem_instant", |lookup| (lookup.bucket("shares"),))
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(&public_key)],
+    ).expect_commit_success();
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, staking_pool, "get_value_per_share", manifest_args!()), dec!("1.0255"));
+
+    call_with_badge(
+        &mut test_runner, &public_key, account, market.admin_badge, market.controller, "set_risk_params",
+        manifest_args!(staking_share_token, risk_params(dec!("0.6"), dec!("0.8"), dec!("0.1")))
+    ).expect_commit_success();
+    call_with_badge(&mut test_runner, &public_key, account, market.cdp_admin_badge, market.cdp, "set_staking_pool", manifest_args!(staking_share_token, staking_pool))
+        .expect_commit_success();
+
+    let manifest = ManifestBuilder::new()
+        .withdraw_from_account(account, staking_share_token, dec!("100"))
+        .take_all_from_worktop(staking_share_token, "collateral")
+        .call_method_with_name_lookup(market.cdp, "open_position", |lookup| (lookup.bucket("collateral"),))
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    let receipt = test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(&public_key)],
+    );
+    let cdp_id = get_received_non_fungible(&receipt, account, market.cdp_token);
+
+    // the shares are worth 102.55 XRD, 82.04 at the liquidation threshold.
+    call_with_cdp(&mut test_runner, &public_key, account, &market, &cdp_id, "borrow_variable",
+        |lookup| (lookup.proof("cdp"), XRD, dec!("50"))).expect_commit_success();
+    assert_eq!(get_health_factor(&mut test_runner, &market, &cdp_id), dec!("1.6408"));
+}
+
+#[test]
+fn test_cdp_swap_rate_mode_takes_the_target_mode() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let market = instantiate_lending_market(&mut test_runner, package_address, &public_key, account);
+    let cdp_id = open_position(&mut test_runner, &public_key, account, &market, dec!("100"));
+    let swap = |test_runner: &mut DefaultTestRunner, to_stable: bool| call_with_cdp(
+        test_runner, &public_key, account, &market, &cdp_id, "swap_rate_mode", |lookup| (lookup.proof("cdp"), XRD, to_stable)
+    );
+    let stable_total = |test_runner: &mut DefaultTestRunner| call_and_decode::<(Decimal, Decimal)>(
+        test_runner, market.pool, "get_stable_loan_total", manifest_args!()
+    ).0;
+
+    call_with_cdp(&mut test_runner, &public_key, account, &market, &cdp_id, "borrow_variable",
+        |lookup| (lookup.proof("cdp"), XRD, dec!("100"))).expect_commit_success();
+    // there is no stable loan to swap to variable.
+    swap(&mut test_runner, false).expect_commit_failure();
+    swap(&mut test_runner, true).expect_commit_success();
+    assert_eq!(stable_total(&mut test_runner), dec!("100"));
+    // nor is there a variable loan left to swap to stable.
+    swap(&mut test_runner, true).expect_commit_failure();
+
+    // with both loans, swapping to stable would need a second stable loan.
+    call_with_cdp(&mut test_runner, &public_key, account, &market, &cdp_id, "borrow_variable",
+        |lookup| (lookup.proof("cdp"), XRD, dec!("50"))).expect_commit_success();
+    swap(&mut test_runner, true).expect_commit_failure();
+    swap(&mut test_runner, false).expect_commit_success();
+    assert_eq!(stable_total(&mut test_runner), Decimal::ZERO);
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, market.pool, "get_loan_value", manifest_args!()), dec!("150"));
+}
+
+fn liquidate(
+    test_runner: &mut DefaultTestRunner,
+    public_key: &Secp256k1PublicKey,
+    account: ComponentAddress,
+    market: &LendingMarket,
+    cdp_id: &NonFungibleLocalId,
+    amount: Decimal
+) -> TransactionReceipt {
+    let manifest = ManifestBuilder::new()
+        .withdraw_from_account(account, XRD, amount)
+        .take_all_from_worktop(XRD, "repay")
+        .call_method_with_name_lookup(market.cdp, "liquidate", |lookup| (cdp_id.clone(), market.collateral, lookup.bucket("repay")))
+        .call_method(
+            account,
+            "deposit_batch",
+            manifest_args!(ManifestExpression::EntireWorktop),
+        )
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(public_key)],
+    )
+}
+
+#[test]
+fn test_liquidation_rejects_invalid_params_and_healthy_positions() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let market = instantiate_lending_market(&mut test_runner, package_address, &public_key, account);
+    let (cdp, cdp_admin_badge) = (market.cdp, market.cdp_admin_badge);
+
+    call_with_badge(&mut test_runner, &public_key, account, cdp_admin_badge, cdp, "set_liquidation_params", manifest_args!(Decimal::ZERO, dec!("0.2")))
+        .expect_commit_failure();
+    call_with_badge(&mut test_runner, &public_key, account, cdp_admin_badge, cdp, "set_liquidation_params", manifest_args!(dec!("1.1"), dec!("0.2")))
+        .expect_commit_failure();
+    call_with_badge(&mut test_runner, &public_key, account, cdp_admin_badge, cdp, "set_liquidation_params", manifest_args!(Decimal::ONE, dec!("1.1")))
+        .expect_commit_failure();
+    call_with_badge(&mut test_runner, &public_key, account, cdp_admin_badge, cdp, "set_liquidation_params", manifest_args!(Decimal::ONE, dec!("0.2")))
+        .expect_commit_success();
+
+    // a position without debt is never liquidated, whatever the price of its collateral.
+    let cdp_id = open_position(&mut test_runner, &public_key, account, &market, dec!("100"));
+    set_price(&mut test_runner, &public_key, market.oracle, market.collateral, dec!("9")).expect_commit_success();
+    liquidate(&mut test_runner, &public_key, account, &market, &cdp_id, dec!("10")).expect_commit_failure();
+}
+
+// opens a position of 100 collateral borrowing 600 XRD.
+fn open_position_in_debt(
+    test_runner: &mut DefaultTestRunner,
+    public_key: &Secp256k1PublicKey,
+    account: ComponentAddress,
+    market: &LendingMarket
+) -> NonFungibleLocalId {
+    let cdp_id = open_position(test_runner, public_key, account, market, dec!("100"));
+    call_with_cdp(test_runner, public_key, account, market, &cdp_id, "borrow_variable",
+        |lookup| (lookup.proof("cdp"), XRD, dec!("600"))).expect_commit_success();
+    cdp_id
+}
+
+#[test]
+fn test_liquidation_repay_is_capped_by_the_close_factor() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (liquidator_public_key, _, liquidator) = test_runner.new_allocated_account();
+    let market = instantiate_lending_market(&mut test_runner, package_address, &public_key, account);
+    // the collateral has a pool, so it has an insurance to take its share of the bonus.
+    let receipt = call_with_badge(
+        &mut test_runner, &public_key, account, market.admin_badge, market.controller, "new_pool",
+        manifest_args!(market.collateral, market.interest_model, InterestModel::Default, dec!("0.1"), risk_params(dec!("0.6"), dec!("0.8"), dec!("0.1")))
+    );
+    let (collateral_pool, _) = receipt.expect_commit_success().output::<(ComponentAddress, ResourceAddress)>(1);
+    let cdp_id = open_position_in_debt(&mut test_runner, &public_key, account, &market);
+
+    liquidate(&mut test_runner, &liquidator_public_key, liquidator, &market, &cdp_id, dec!("1000")).expect_commit_failure();
+    // the oracle moves the price at most a tenth per epoch, the band is widened to crash it at once.
+    call_with_badge(&mut test_runner, &public_key, account, market.oracle_admin_badge, market.oracle, "set_price_band", manifest_args!(MAX_AGE_EPOCHS, dec!("0.5")))
+        .expect_commit_success();
+    // at 8.1 the position is still healthy.
+    set_price(&mut test_runner, &public_key, market.oracle, market.collateral, dec!("8.1")).expect_commit_success();
+    liquidate(&mut test_runner, &liquidator_public_key, liquidator, &market, &cdp_id, dec!("1000")).expect_commit_failure();
+    set_price(&mut test_runner, &public_key, market.oracle, market.collateral, dec!("7.29")).expect_commit_success();
+    assert_eq!(get_health_factor(&mut test_runner, &market, &cdp_id), dec!("0.972"));
+
+    // the close factor lets half of the debt be repaid, a fifth of the seized bonus goes to the insurance.
+    let receipt = liquidate(&mut test_runner, &liquidator_public_key, liquidator, &market, &cdp_id, dec!("1000"));
+    let seize_amount = dec!("300") * dec!("1.1") / dec!("7.29");
+    let insurance_amount = seize_amount * dec!("0.02") / dec!("1.1");
+    assert_eq!(
+        get_events::<LiquidationEvent>(&test_runner, &receipt, market.cdp, "LiquidationEvent"),
+        vec![LiquidationEvent{
+            cdp_id: cdp_id.clone(),
+            debt_token: XRD,
+            repay_amount: dec!("300"),
+            collateral_token: market.collateral,
+            seize_amount,
+            insurance_amount
+        }]
+    );
+    assert_eq!(test_runner.get_component_balance(liquidator, market.collateral), seize_amount - insurance_amount);
+    assert_eq!(call_and_decode::<(Decimal, Decimal)>(&mut test_runner, collateral_pool, "get_insurance", manifest_args!()).0, insurance_amount);
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, market.pool, "get_loan_value", manifest_args!()), dec!("300"));
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, market.pool, "get_available", manifest_args!()), dec!("1700"));
+    assert!(get_health_factor(&mut test_runner, &market, &cdp_id) > Decimal::ONE);
+}
+
+#[test]
+fn test_liquidation_seizes_no_more_than_the_collateral() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let (liquidator_public_key, _, liquidator) = test_runner.new_allocated_account();
+    let market = instantiate_lending_market(&mut test_runner, package_address, &public_key, account);
+    call_with_badge(&mut test_runner, &public_key, account, market.cdp_admin_badge, market.cdp, "set_liquidation_params", manifest_args!(Decimal::ONE, dec!("0.2")))
+        .expect_commit_success();
+    let cdp_id = open_position_in_debt(&mut test_runner, &public_key, account, &market);
+
+    // at 5.9049 the collateral is worth 590.49, less than the debt with the bonus.
+    call_with_badge(&mut test_runner, &public_key, account, market.oracle_admin_badge, market.oracle, "set_price_band", manifest_args!(MAX_AGE_EPOCHS, dec!("0.5")))
+        .expect_commit_success();
+    set_price(&mut test_runner, &public_key, market.oracle, market.collateral, dec!("5.9049")).expect_commit_success();
+
+    let receipt = liquidate(&mut test_runner, &liquidator_public_key, liquidator, &market, &cdp_id, dec!("700"));
+    // the repayment shrinks to what the whole collateral covers with the bonus. The collateral
+    // has no pool, so the insurance share of the bonus stays with the position.
+    let repay_amount = dec!("590.49") / dec!("1.1");
+    let seized = repay_amount * dec!("1.1") / dec!("5.9049");
+    let seize_amount = seized - seized * dec!("0.02") / dec!("1.1");
+    assert_eq!(
+        get_events::<LiquidationEvent>(&test_runner, &receipt, market.cdp, "LiquidationEvent"),
+        vec![LiquidationEvent{
+            cdp_id: cdp_id.clone(),
+            debt_token: XRD,
+            repay_amount,
+            collateral_token: market.collateral,
+            seize_amount,
+            insurance_amount: Decimal::ZERO
+        }]
+    );
+    assert_eq!(test_runner.get_component_balance(liquidator, market.collateral), seize_amount);
+    assert_eq!(call_and_decode::<(Decimal, Decimal)>(&mut test_runner, market.pool, "get_insurance", manifest_args!()).0, Decimal::ZERO);
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, market.pool, "get_loan_value", manifest_args!()), dec!("600") - repay_amount);
+    // the collateral left to the position still counts.
+    let health_factor = get_health_factor(&mut test_runner, &market, &cdp_id);
+    assert!(health_factor > Decimal::ZERO && health_factor < Decimal::ONE);
+}
+
+#[test]
+fn test_controller_creates_pools_and_validates_risk_params() {
+    let (mut test_runner, public_key, account, package_address) = setup();
+    let market = instantiate_lending_market(&mut test_runner, package_address, &public_key, account);
+    let (controller, admin_badge) = (market.controller, market.admin_badge);
+
+    assert_eq!(call_and_decode::<ComponentAddress>(&mut test_runner, controller, "get_pool", manifest_args!(XRD)), market.pool);
+    assert_eq!(
+        call_and_decode::<ResourceAddress>(&mut test_runner, controller, "get_deposit_share_token", manifest_args!(XRD)),
+        market.deposit_share_token
+    );
+    assert_eq!(
+        call_and_decode::<RiskParams>(&mut test_runner, controller, "get_risk_params", manifest_args!(XRD)),
+        risk_params(dec!("0.5"), dec!("0.7"), dec!("0.05"))
+    );
+
+    // one pool per underlying token.
+    let new_pool = |test_runner: &mut DefaultTestRunner, underlying_token: ResourceAddress| {
+        call_with_badge(
+            test_runner, &public_key, account, admin_badge, controller, "new_pool",
+            manifest_args!(underlying_token, market.interest_model, InterestModel::Default, dec!("0.1"), risk_params(dec!("0.5"), dec!("0.7"), dec!("0.05")))
+        )
+    };
+    new_pool(&mut test_runner, XRD).expect_commit_failure();
+    let token = test_runner.create_fungible_resource(dec!("1000"), 18, account);
+    let (pool, _) = new_pool(&mut test_runner, token).expect_commit_success().output::<(ComponentAddress, ResourceAddress)>(1);
+    assert_eq!(call_and_decode::<ComponentAddress>(&mut test_runner, controller, "get_pool", manifest_args!(token)), pool);
+    assert_eq!(call_and_decode::<Vec<ResourceAddress>>(&mut test_runner, controller, "get_underlying_tokens", manifest_args!()).len(), 2);
+
+    let set_risk_params = |test_runner: &mut DefaultTestRunner, params: RiskParams| {
+        call_with_badge(test_runner, &public_key, account, admin_badge, controller, "set_risk_params", manifest_args!(token, params))
+    };
+    set_risk_params(&mut test_runner, risk_params(dec!("0.8"), dec!("0.7"), dec!("0.05"))).expect_commit_failure();
+    set_risk_params(&mut test_runner, risk_params(dec!("0.5"), dec!("0.8"), dec!("0.25"))).expect_commit_failure();
+    set_risk_params(&mut test_runner, risk_params(dec!("0.5"), dec!("0.8"), dec!("-0.1"))).expect_commit_failure();
+    // the caps of an existing pool follow its risk parameters.
+    let params = RiskParams { supply_cap: dec!("5000"), borrow_cap: dec!("1000"), ..risk_params(dec!("0.5"), dec!("0.8"), dec!("0.2")) };
+    set_risk_params(&mut test_runner, params.clone()).expect_commit_success();
+    assert_eq!(call_and_decode::<RiskParams>(&mut test_runner, controller, "get_risk_params", manifest_args!(token)), params);
+    assert_eq!(call_and_decode::<(Decimal, Decimal)>(&mut test_runner, pool, "get_caps", manifest_args!()), (dec!("5000"), dec!("1000")));
+
+    // only the admin sets the risk parameters.
+    let manifest = ManifestBuilder::new()
+        .call_method(controller, "set_risk_params", manifest_args!(token, risk_params(dec!("0.5"), dec!("0.7"), dec!("0.05"))))
+        .build();
+    test_runner.execute_manifest_ignoring_fee(
+        manifest,
+        vec![NonFungibleGlobalId::from_public_key(&public_key)],
+    ).expect_commit_failure();
 
-    Ok(())
+    // borrowing is left to the CDP manager, not even the admin borrows directly.
+    call_with_badge(&mut test_runner, &public_key, account, admin_badge, controller, "borrow_variable", manifest_args!(XRD, dec!("100")))
+        .expect_commit_failure();
+    assert_eq!(call_and_decode::<Decimal>(&mut test_runner, market.pool, "get_loan_value", manifest_args!()), Decimal::ZERO);
 }